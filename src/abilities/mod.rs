@@ -0,0 +1,126 @@
+//! Networked abilities built on leafwing_abilities.
+//!
+//! Cooldowns and charges live in [`CooldownState`] and [`ChargeState`] components on the
+//! character. They are predicted, and ticked in `FixedUpdate` with the fixed timestep rather
+//! than by `AbilityPlugin` in `PreUpdate`, so that a rollback replays them tick for tick.
+use bevy::ecs::query::QueryData;
+use bevy::math::Vec3;
+use bevy::prelude::{Bundle, Res, Time};
+use leafwing_abilities::prelude::{ChargeState, Charges, Cooldown, CooldownState};
+use leafwing_input_manager::prelude::ActionState;
+
+use crate::character::config::MovementConfig;
+use crate::combat::hitscan::FIRE_COOLDOWN_SECS;
+use crate::combat::projectile::PROJECTILE_COOLDOWN_SECS;
+use crate::netcode::protocol::{Ability, CharacterAction};
+use crate::netcode::shared::CharacterQueryItem;
+
+/// Seconds it takes for a spent dash charge to come back
+pub(crate) const DASH_RECHARGE_SECS: f32 = 2.0;
+/// Number of dashes that can be stored
+pub(crate) const DASH_CHARGES: u8 = 2;
+
+#[derive(Bundle)]
+pub(crate) struct AbilitiesBundle {
+    action_state: ActionState<Ability>,
+    cooldowns: CooldownState<Ability>,
+    charges: ChargeState<Ability>,
+}
+
+impl Default for AbilitiesBundle {
+    fn default() -> Self {
+        Self {
+            action_state: ActionState::default(),
+            cooldowns: CooldownState::new([
+                (Ability::Dash, Cooldown::from_secs(DASH_RECHARGE_SECS)),
+                (Ability::Fire, Cooldown::from_secs(FIRE_COOLDOWN_SECS)),
+                (
+                    Ability::Projectile,
+                    Cooldown::from_secs(PROJECTILE_COOLDOWN_SECS),
+                ),
+            ]),
+            charges: ChargeState::default()
+                .set(Ability::Dash, Charges::replenish_one(DASH_CHARGES))
+                .build(),
+        }
+    }
+}
+
+#[derive(QueryData)]
+#[query_data(mutable, derive(Debug))]
+pub struct AbilityQuery {
+    pub cooldowns: &'static mut CooldownState<Ability>,
+    pub charges: Option<&'static mut ChargeState<Ability>>,
+}
+
+/// Spend a charge of the ability, or start its cooldown if it doesn't use charges.
+///
+/// Returns false if the ability is not ready.
+pub(crate) fn trigger_ability(ability: Ability, abilities: &mut AbilityQueryItem) -> bool {
+    match abilities.charges.as_deref_mut() {
+        Some(charges) if charges.get(&ability).is_some() => charges.expend(&ability).is_ok(),
+        _ => abilities.cooldowns.trigger(&ability).is_ok(),
+    }
+}
+
+/// Trigger the abilities pressed this tick and apply their shared effects.
+///
+/// Returns the abilities that were activated, so that the server and client can add their
+/// own side of the effect (e.g. hit detection on the server, tracers on the client).
+pub fn apply_ability_action(
+    time: &Res<Time>,
+    config: &MovementConfig,
+    action_state: &ActionState<Ability>,
+    character_action_state: &ActionState<CharacterAction>,
+    abilities: &mut AbilityQueryItem,
+    character: &mut CharacterQueryItem,
+) -> Vec<Ability> {
+    // Recharge first so a charge that comes back this tick can be used this tick
+    abilities
+        .cooldowns
+        .tick(time.delta(), abilities.charges.as_deref_mut());
+
+    let mut activated = Vec::new();
+    for ability in action_state.get_just_pressed() {
+        if !trigger_ability(ability, abilities) {
+            continue;
+        }
+        match ability {
+            Ability::Dash => apply_dash(config, character_action_state, character),
+            // Hits are decided by the server, see `combat::hitscan`
+            Ability::Fire => {}
+            // Spawning differs between server and client, see `combat::projectile`
+            Ability::Projectile => {}
+        }
+        activated.push(ability);
+    }
+    activated
+}
+
+/// Dash in the move direction, or along the current velocity if there is no move input.
+///
+/// The horizontal velocity is replaced rather than added to, so a dash always covers the
+/// same distance regardless of how fast the character was already moving.
+fn apply_dash(
+    config: &MovementConfig,
+    character_action_state: &ActionState<CharacterAction>,
+    character: &mut CharacterQueryItem,
+) {
+    let move_dir = character_action_state
+        .axis_pair(&CharacterAction::Move)
+        .clamp_length_max(1.0);
+    let ground_linear_velocity = Vec3::new(
+        character.linear_velocity.x,
+        0.0,
+        character.linear_velocity.z,
+    );
+    let dash_dir = Vec3::new(-move_dir.x, 0.0, move_dir.y)
+        .try_normalize()
+        .or_else(|| ground_linear_velocity.try_normalize())
+        .unwrap_or(Vec3::Z);
+
+    let dash_velocity = dash_dir * config.dash_speed;
+    character
+        .external_impulse
+        .apply_impulse((dash_velocity - ground_linear_velocity) * character.mass.0);
+}
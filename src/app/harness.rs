@@ -0,0 +1,315 @@
+//! Headless server and clients running in one process, stepped in lockstep
+//!
+//! The apps only use MinimalPlugins: without a RenderPlugin the SharedPlugin skips the render
+//! plugin, and without a window the clients don't read any device, so their ActionStates are
+//! driven by whoever steps the harness. The clients talk to the server over crossbeam channels
+//! like in the ClientAndServer mode, and every app is updated with the same mocked clock.
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::bail;
+use bevy::diagnostic::{DiagnosticPath, DiagnosticsPlugin, DiagnosticsStore};
+use bevy::hierarchy::HierarchyPlugin;
+use bevy::input::InputPlugin;
+use bevy::log::{Level, LogPlugin};
+use bevy::prelude::{default, App, Entity, Plugin, State, With, World};
+use bevy::state::app::StatesPlugin;
+use bevy::time::TimeUpdateStrategy;
+use bevy::transform::TransformPlugin;
+use bevy::utils::Instant;
+use bevy::MinimalPlugins;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::client::config::ClientConfig;
+use lightyear::client::plugin::ClientPlugins;
+use lightyear::connection::client;
+use lightyear::prelude::client::{ClientTransport, NetworkingState, Predicted};
+use lightyear::prelude::server::ServerTransport;
+use lightyear::prelude::ReplicationConfig;
+use lightyear::server::config::ServerConfig;
+use lightyear::server::plugin::ServerPlugins;
+use lightyear::shared::config::Mode;
+use lightyear::shared::replication::components::Controlled;
+use lightyear::transport::io::IoDiagnosticsPlugin;
+use lightyear::transport::LOCAL_SOCKET;
+
+use crate::app::settings::{build_client_netcode_config, build_server_netcode_config, Settings};
+use crate::app::shared::{shared_config, FIXED_TIMESTEP_HZ, REPLICATION_INTERVAL};
+use crate::netcode::protocol::{CharacterAction, CharacterMarker};
+use crate::ZinnobreIronSettings;
+
+/// Client ids handed out to the harness clients, in order
+pub(crate) const FIRST_CLIENT_ID: u64 = 1000;
+/// Frames to wait for the clients to connect and get their characters
+pub(crate) const CONNECT_FRAMES: usize = 600;
+/// Frames for the characters to land and the interpolation buffers to fill
+pub(crate) const SETTLE_FRAMES: usize = 64;
+
+pub(crate) struct Harness {
+    pub(crate) server_app: App,
+    server_config: ServerConfig,
+    pub(crate) client_apps: Vec<App>,
+    client_configs: Vec<ClientConfig>,
+    current_time: Instant,
+    frame_duration: Duration,
+    last_server_frame: Duration,
+}
+
+impl Harness {
+    pub(crate) fn new(settings: &Settings, num_clients: usize) -> Self {
+        let mut channels = Vec::with_capacity(num_clients);
+        let mut client_configs = Vec::with_capacity(num_clients);
+        for i in 0..num_clients {
+            let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
+            let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+            // The server tells the clients apart by the address of their channel
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), i as u16 + 1);
+            channels.push((addr, to_server_recv, from_server_send));
+
+            let net_config = build_client_netcode_config(
+                FIRST_CLIENT_ID + i as u64,
+                LOCAL_SOCKET,
+                settings.client.conditioner.as_ref(),
+                &settings.shared,
+                ClientTransport::LocalChannel {
+                    recv: from_server_recv,
+                    send: to_server_send,
+                },
+            );
+            client_configs.push(headless_client_config(net_config));
+        }
+
+        let server_config = ServerConfig {
+            shared: shared_config(Mode::Separate),
+            net: vec![build_server_netcode_config(
+                settings.server.conditioner.as_ref(),
+                &settings.shared,
+                ServerTransport::Channels { channels },
+            )],
+            replication: ReplicationConfig {
+                send_interval: REPLICATION_INTERVAL,
+                ..default()
+            },
+            ..default()
+        };
+
+        Self {
+            server_app: headless_app(),
+            server_config,
+            client_apps: (0..num_clients).map(|_| headless_app()).collect(),
+            client_configs,
+            current_time: Instant::now(),
+            frame_duration: Duration::from_secs_f64(1.0 / FIXED_TIMESTEP_HZ),
+            last_server_frame: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn update_lightyear_client_config(
+        &mut self,
+        f: impl Fn(&mut ClientConfig),
+    ) -> &mut Self {
+        self.client_configs.iter_mut().for_each(f);
+        self
+    }
+
+    pub(crate) fn add_lightyear_plugins(&mut self) -> &mut Self {
+        self.server_app.add_plugins(ServerPlugins {
+            config: self.server_config.clone(),
+        });
+        for (app, config) in self.client_apps.iter_mut().zip(&self.client_configs) {
+            app.add_plugins(ClientPlugins {
+                config: config.clone(),
+            });
+        }
+        self
+    }
+
+    pub(crate) fn add_user_plugins(
+        &mut self,
+        client_plugin: impl Plugin + Clone,
+        server_plugin: impl Plugin,
+        shared_plugin: impl Plugin + Clone,
+    ) -> &mut Self {
+        for app in &mut self.client_apps {
+            app.add_plugins((client_plugin.clone(), shared_plugin.clone()));
+        }
+        self.server_app.add_plugins((server_plugin, shared_plugin));
+        self
+    }
+
+    /// Finish building the apps and step them until every client is connected and controls
+    /// its character
+    pub(crate) fn connect(&mut self, max_frames: usize) -> anyhow::Result<()> {
+        for app in self.apps_mut() {
+            app.finish();
+            app.cleanup();
+        }
+        self.wait_until(max_frames, |harness| {
+            (0..harness.client_apps.len()).all(|client| {
+                harness.is_connected(client) && harness.controlled_character(client).is_some()
+            })
+        })
+    }
+
+    fn apps_mut(&mut self) -> impl Iterator<Item = &mut App> {
+        std::iter::once(&mut self.server_app).chain(self.client_apps.iter_mut())
+    }
+
+    /// Move the mocked clock of every app forward
+    pub(crate) fn advance_time(&mut self, duration: Duration) {
+        self.current_time += duration;
+        let current_time = self.current_time;
+        for app in self.apps_mut() {
+            app.insert_resource(TimeUpdateStrategy::ManualInstant(current_time));
+        }
+    }
+
+    /// Run one frame of the server, then one frame of every client
+    pub(crate) fn frame_step(&mut self) {
+        self.advance_time(self.frame_duration);
+        let start = Instant::now();
+        self.server_app.update();
+        self.last_server_frame = start.elapsed();
+        for app in &mut self.client_apps {
+            app.update();
+        }
+    }
+
+    /// Wall time the server took for the last frame, which runs one tick
+    pub(crate) fn last_server_frame(&self) -> Duration {
+        self.last_server_frame
+    }
+
+    pub(crate) fn frame_step_n(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.frame_step();
+        }
+    }
+
+    /// Step until the condition holds, failing after `max_frames`
+    pub(crate) fn wait_until(
+        &mut self,
+        max_frames: usize,
+        condition: impl Fn(&mut Harness) -> bool,
+    ) -> anyhow::Result<()> {
+        for _ in 0..max_frames {
+            if condition(self) {
+                return Ok(());
+            }
+            self.frame_step();
+        }
+        if condition(self) {
+            return Ok(());
+        }
+        bail!("condition still false after {max_frames} frames")
+    }
+
+    pub(crate) fn client_world_mut(&mut self, client: usize) -> &mut World {
+        self.client_apps[client].world_mut()
+    }
+
+    pub(crate) fn is_connected(&self, client: usize) -> bool {
+        self.client_apps[client]
+            .world()
+            .get_resource::<State<NetworkingState>>()
+            .is_some_and(|state| *state.get() == NetworkingState::Connected)
+    }
+
+    /// The predicted character the client controls
+    pub(crate) fn controlled_character(&mut self, client: usize) -> Option<Entity> {
+        let world = self.client_world_mut(client);
+        world
+            .query_filtered::<Entity, (With<Predicted>, With<Controlled>, With<CharacterMarker>)>()
+            .get_single(world)
+            .ok()
+    }
+
+    /// Change the inputs of the character the client controls, they stay as set until changed
+    /// again
+    pub(crate) fn set_input(
+        &mut self,
+        client: usize,
+        f: impl FnOnce(&mut ActionState<CharacterAction>),
+    ) -> anyhow::Result<()> {
+        let Some(entity) = self.controlled_character(client) else {
+            bail!("client {client} doesn't control a character");
+        };
+        let world = self.client_world_mut(client);
+        let Some(mut action_state) = world.get_mut::<ActionState<CharacterAction>>(entity) else {
+            bail!("client {client}'s character has no ActionState");
+        };
+        f(&mut action_state);
+        Ok(())
+    }
+
+    /// Latest value of one of the client's diagnostics
+    pub(crate) fn client_diagnostic(&self, client: usize, path: &DiagnosticPath) -> Option<f64> {
+        self.client_apps[client]
+            .world()
+            .get_resource::<DiagnosticsStore>()?
+            .get(path)?
+            .value()
+    }
+
+    /// Kilobytes per second the client currently receives
+    pub(crate) fn kilobytes_in(&self, client: usize) -> f64 {
+        self.client_diagnostic(client, &IoDiagnosticsPlugin::BYTES_IN)
+            .unwrap_or_default()
+    }
+
+    /// Kilobytes per second the server currently sends, to all the clients together
+    pub(crate) fn server_kilobytes_out(&self) -> f64 {
+        self.server_app
+            .world()
+            .get_resource::<DiagnosticsStore>()
+            .and_then(|store| store.get(&IoDiagnosticsPlugin::BYTES_OUT)?.value())
+            .unwrap_or_default()
+    }
+}
+
+/// Build a harness with the game's plugins, more can be added before connecting
+pub(crate) fn game_harness(settings: &ZinnobreIronSettings, num_clients: usize) -> Harness {
+    let mut harness = Harness::new(&settings.common, num_clients);
+    let mut client_plugin = settings.client_plugin();
+    client_plugin.misprediction_log = None;
+    let mut server_plugin = settings.server_plugin();
+    server_plugin.input_recording = None;
+    harness
+        .update_lightyear_client_config(|config| settings.configure_client(config))
+        .add_lightyear_plugins()
+        .add_user_plugins(client_plugin, server_plugin, settings.shared_plugin());
+    harness
+}
+
+pub(crate) fn headless_client_config(net_config: client::NetConfig) -> ClientConfig {
+    ClientConfig {
+        shared: shared_config(Mode::Separate),
+        net: net_config,
+        replication: ReplicationConfig {
+            send_interval: REPLICATION_INTERVAL,
+            ..default()
+        },
+        ..default()
+    }
+}
+
+pub(crate) fn headless_app() -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        StatesPlugin,
+        TransformPlugin,
+        HierarchyPlugin,
+        DiagnosticsPlugin,
+        InputPlugin,
+    ));
+    app
+}
+
+/// The harness apps don't log by themselves, there would be one global logger per app
+pub(crate) fn init_logging() {
+    App::new().add_plugins(LogPlugin {
+        level: Level::INFO,
+        filter: "wgpu=error,bevy_render=info,bevy_ecs=warn".to_string(),
+        ..default()
+    });
+}
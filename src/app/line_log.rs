@@ -0,0 +1,87 @@
+//! Text files written one line at a time, for diagnostics and recordings.
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use bevy::app::AppExit;
+use bevy::log::warn;
+use bevy::prelude::{EventReader, ResMut, Resource};
+
+/// Flush every this many lines, so that a crash loses little
+const FLUSH_INTERVAL: usize = 64;
+
+/// Buffered file written line by line, flushed every few lines and when dropped.
+///
+/// A write error closes the file instead of failing the caller: losing a diagnostics file
+/// must not stop the game.
+#[derive(Default)]
+pub(crate) struct LineLog {
+    /// What the file is, for log messages
+    name: &'static str,
+    writer: Option<BufWriter<File>>,
+    lines: usize,
+}
+
+impl LineLog {
+    /// Create the file at `path` and write its first line
+    pub(crate) fn create(name: &'static str, path: &str, first_line: impl Display) -> Self {
+        let writer = File::create(path)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                writeln!(writer, "{first_line}")?;
+                Ok(writer)
+            })
+            .map_err(|e| warn!("Could not create {name} {path}: {e}"))
+            .ok();
+        Self {
+            name,
+            writer,
+            lines: 0,
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    pub(crate) fn write_line(&mut self, line: impl Display) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        if let Err(e) = writeln!(writer, "{line}") {
+            warn!("Could not write to the {}, closing it: {e}", self.name);
+            self.writer = None;
+            return;
+        }
+        self.lines += 1;
+        if self.lines % FLUSH_INTERVAL == 0 {
+            self.flush();
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        if let Err(e) = writer.flush() {
+            warn!("Could not flush the {}, closing it: {e}", self.name);
+            self.writer = None;
+        }
+    }
+}
+
+impl Drop for LineLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Flush the resource's file when the app exits, the process may end before it is dropped
+pub(crate) fn flush_on_exit<R: Resource + AsMut<LineLog>>(
+    mut exits: EventReader<AppExit>,
+    mut resource: ResMut<R>,
+) {
+    if exits.read().last().is_some() {
+        (*resource).as_mut().flush();
+    }
+}
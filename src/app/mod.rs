@@ -1,8 +1,11 @@
+pub(crate) mod harness;
+pub(crate) mod line_log;
 pub(crate) mod settings;
 pub(crate) mod shared;
 
 use crate::app::server::plugin::ServerPlugins;
 use crate::app::settings::{build_client_netcode_config, Settings};
+use crate::bots::BotPolicy;
 use bevy::log::{Level, LogPlugin};
 use bevy::prelude::App;
 use bevy::prelude::AssetPlugin;
@@ -13,7 +16,7 @@ use bevy::utils::default;
 use bevy::DefaultPlugins;
 use bevy::MinimalPlugins;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use lightyear::client::plugin::ClientPlugins;
 use lightyear::prelude::client::ClientTransport;
 use lightyear::prelude::server::ServerTransport;
@@ -32,6 +35,45 @@ use std::time::Duration;
 
 #[derive(Parser, PartialEq, Debug)]
 pub enum Cli {
+    #[command(flatten)]
+    Game(GameMode),
+    /// Run headless bot clients against the server until the time is up, then report their
+    /// connection statistics
+    Bots {
+        #[arg(short, long, default_value_t = 1)]
+        count: usize,
+        #[arg(short, long, default_value_t = 60)]
+        seconds: u64,
+        #[arg(short, long, value_enum, default_value_t)]
+        policy: BotPolicy,
+    },
+    /// Measure the server's tick time and bandwidth for increasing player counts, and write
+    /// the results to a report
+    Benchmark {
+        /// Largest number of simulated players
+        #[arg(short, long, default_value_t = 32)]
+        max_players: usize,
+        /// Players added between two measurements
+        #[arg(short, long, default_value_t = 4)]
+        step: usize,
+        /// Ticks measured for each player count
+        #[arg(short, long, default_value_t = 640)]
+        ticks: usize,
+        #[arg(short, long, default_value = "benchmark.ron")]
+        output: String,
+    },
+    /// Replay a session recorded by the server and check that it reproduces the recorded
+    /// trajectories
+    Replay {
+        /// Recording written by the server, see the `input_recording` setting
+        #[arg(short, long)]
+        file: String,
+    },
+}
+
+/// The ways to run the game itself, the other commands build their own headless apps
+#[derive(Subcommand, PartialEq, Debug)]
+pub enum GameMode {
     HostServer {
         #[arg(short, long, default_value = None)]
         client_id: Option<u64>,
@@ -89,9 +131,9 @@ pub enum Apps {
 }
 
 impl Apps {
-    pub fn new(settings: Settings, cli: Cli) -> Self {
-        match cli {
-            Cli::HostServer { client_id } => {
+    pub fn new(settings: Settings, mode: GameMode) -> Self {
+        match mode {
+            GameMode::HostServer { client_id } => {
                 let client_net_config = client::NetConfig::Local {
                     id: client_id.unwrap_or(settings.client.client_id),
                 };
@@ -103,7 +145,7 @@ impl Apps {
                     server_config,
                 }
             }
-            Cli::ClientAndServer { client_id } => {
+            GameMode::ClientAndServer { client_id } => {
                 let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
                 let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
                 let transport_config = ClientTransport::LocalChannel {
@@ -131,11 +173,11 @@ impl Apps {
                     server_config,
                 }
             }
-            Cli::Server => {
+            GameMode::Server => {
                 let (app, config) = server_app(settings, vec![]);
                 Apps::Server { app, config }
             }
-            Cli::Client { client_id } => {
+            GameMode::Client { client_id } => {
                 let server_addr = SocketAddr::new(
                     settings.client.server_addr.into(),
                     settings.client.server_port,
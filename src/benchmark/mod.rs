@@ -0,0 +1,222 @@
+//! Server tick time and bandwidth benchmark
+//!
+//! `benchmark` runs a headless server with simulated clients on the Harness, for increasing
+//! player counts. Every player walks around while the server's tick duration, the bandwidth
+//! the server sends and the clients receive, and the physics updates received by the clients
+//! are measured, and the results are written to a RON report that can be compared between
+//! commits.
+//!
+//! The link conditioners of the settings are ignored, the benchmark measures the game rather
+//! than the simulated network. Build with the `GIT_COMMIT` environment variable set, e.g. to
+//! `$(git rev-parse HEAD)`, to record the commit in the report.
+use std::fs;
+use std::time::Duration;
+
+use anyhow::Context;
+use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+use bevy::app::{App, Plugin, Update};
+use bevy::asset::ron;
+use bevy::log::info;
+use bevy::math::Vec2;
+use bevy::prelude::{Component, EventReader, ResMut, Resource};
+use lightyear::prelude::client::{ComponentUpdateEvent, EntitySpawnEvent};
+use serde::Serialize;
+
+use crate::app::harness::{game_harness, init_logging, Harness, CONNECT_FRAMES, SETTLE_FRAMES};
+use crate::app::shared::FIXED_TIMESTEP_HZ;
+use crate::netcode::protocol::CharacterAction;
+use crate::ZinnobreIronSettings;
+
+/// How often the players change direction
+const TURN_FRAMES: usize = 32;
+
+#[derive(Serialize)]
+struct BenchmarkReport {
+    /// Commit the benchmark was built from, if `GIT_COMMIT` was set at build time
+    commit: Option<&'static str>,
+    tick_hz: f64,
+    /// Ticks measured for each player count
+    ticks_per_stage: usize,
+    stages: Vec<StageReport>,
+}
+
+#[derive(Serialize)]
+struct StageReport {
+    players: usize,
+    /// Server tick duration percentiles, in microseconds
+    tick_p50_us: u64,
+    tick_p90_us: u64,
+    tick_p99_us: u64,
+    tick_max_us: u64,
+    /// Average bytes the server sent per client per second, measured on the server
+    bytes_sent_per_client_per_sec: f64,
+    /// Average bytes each client received per second, measured on the clients
+    bytes_received_per_client_per_sec: f64,
+    /// Position, rotation and velocity updates each client received per second, as replication
+    /// events on the client rather than messages sent by the server
+    component_updates_received_per_client_per_sec: f64,
+    /// Entities each client had replicated to it
+    spawns_received_per_client: f64,
+}
+
+/// Replication events received by a client
+#[derive(Resource, Default)]
+struct ReplicationCounter {
+    physics_updates: u64,
+    spawns: u64,
+}
+
+struct ReplicationCounterPlugin;
+
+impl Plugin for ReplicationCounterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationCounter>();
+        app.add_systems(
+            Update,
+            (
+                count_updates::<Position>,
+                count_updates::<Rotation>,
+                count_updates::<LinearVelocity>,
+                count_updates::<AngularVelocity>,
+                count_spawns,
+            ),
+        );
+    }
+}
+
+fn count_updates<C: Component>(
+    mut counter: ResMut<ReplicationCounter>,
+    mut events: EventReader<ComponentUpdateEvent<C>>,
+) {
+    counter.physics_updates += events.read().count() as u64;
+}
+
+fn count_spawns(
+    mut counter: ResMut<ReplicationCounter>,
+    mut events: EventReader<EntitySpawnEvent>,
+) {
+    counter.spawns += events.read().count() as u64;
+}
+
+/// Run the benchmark for `step`, `2 * step`, ... up to `max_players` players
+pub(crate) fn run(
+    settings: &ZinnobreIronSettings,
+    max_players: usize,
+    step: usize,
+    ticks: usize,
+    output: &str,
+) -> anyhow::Result<()> {
+    init_logging();
+    let mut settings = settings.clone();
+    settings.common.client.conditioner = None;
+    settings.common.server.conditioner = None;
+    let mut stages = Vec::new();
+    for players in (step.max(1)..=max_players).step_by(step.max(1)) {
+        let stage = run_stage(&settings, players, ticks)
+            .with_context(|| format!("benchmark with {players} players failed"))?;
+        info!(
+            players,
+            tick_p50_us = stage.tick_p50_us,
+            tick_p99_us = stage.tick_p99_us,
+            bytes_sent_per_client_per_sec = stage.bytes_sent_per_client_per_sec,
+            component_updates_received_per_client_per_sec =
+                stage.component_updates_received_per_client_per_sec,
+            "Benchmark stage done"
+        );
+        stages.push(stage);
+    }
+
+    let report = BenchmarkReport {
+        commit: option_env!("GIT_COMMIT"),
+        tick_hz: FIXED_TIMESTEP_HZ,
+        ticks_per_stage: ticks,
+        stages,
+    };
+    let serialized = ron::ser::to_string_pretty(&report, ron::ser::PrettyConfig::default())
+        .context("could not serialize the report")?;
+    fs::write(output, serialized).with_context(|| format!("could not write {output}"))?;
+    info!(output, "Wrote the benchmark report");
+    Ok(())
+}
+
+fn run_stage(
+    settings: &ZinnobreIronSettings,
+    players: usize,
+    ticks: usize,
+) -> anyhow::Result<StageReport> {
+    let mut harness = game_harness(settings, players);
+    for app in &mut harness.client_apps {
+        app.add_plugins(ReplicationCounterPlugin);
+    }
+    harness
+        .connect(CONNECT_FRAMES)
+        .context("the clients didn't connect")?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    for app in &mut harness.client_apps {
+        app.insert_resource(ReplicationCounter::default());
+    }
+
+    // Every frame of the harness is one tick
+    let mut tick_durations = Vec::with_capacity(ticks);
+    let mut kilobytes_out = 0.0;
+    let mut kilobytes_in = 0.0;
+    for frame in 0..ticks {
+        if frame % TURN_FRAMES == 0 {
+            walk(&mut harness, frame)?;
+        }
+        harness.frame_step();
+        tick_durations.push(harness.last_server_frame());
+        kilobytes_out += harness.server_kilobytes_out();
+        kilobytes_in += (0..players)
+            .map(|client| harness.kilobytes_in(client))
+            .sum::<f64>();
+    }
+
+    let seconds = ticks as f64 / FIXED_TIMESTEP_HZ;
+    let per_client = |total: f64| total / players as f64;
+    let (physics_updates, spawns) = harness
+        .client_apps
+        .iter()
+        .map(|app| app.world().resource::<ReplicationCounter>())
+        .fold((0, 0), |(physics_updates, spawns), counter| {
+            (
+                physics_updates + counter.physics_updates,
+                spawns + counter.spawns,
+            )
+        });
+
+    tick_durations.sort();
+    Ok(StageReport {
+        players,
+        tick_p50_us: percentile(&tick_durations, 0.5).as_micros() as u64,
+        tick_p90_us: percentile(&tick_durations, 0.9).as_micros() as u64,
+        tick_p99_us: percentile(&tick_durations, 0.99).as_micros() as u64,
+        tick_max_us: percentile(&tick_durations, 1.0).as_micros() as u64,
+        // The diagnostics are in KB/s, averaged over the ticks
+        bytes_sent_per_client_per_sec: per_client(kilobytes_out * 1000.0 / ticks as f64),
+        bytes_received_per_client_per_sec: per_client(kilobytes_in * 1000.0 / ticks as f64),
+        component_updates_received_per_client_per_sec: per_client(physics_updates as f64) / seconds,
+        spawns_received_per_client: per_client(spawns as f64),
+    })
+}
+
+/// Point every player in a different direction, turning them a bit every time
+fn walk(harness: &mut Harness, frame: usize) -> anyhow::Result<()> {
+    let turn = (frame / TURN_FRAMES) as f32;
+    for client in 0..harness.client_apps.len() {
+        let direction = Vec2::from_angle(client as f32 + turn);
+        harness.set_input(client, |action_state| {
+            action_state.set_axis_pair(&CharacterAction::Move, direction)
+        })?;
+    }
+    Ok(())
+}
+
+/// Value below which `fraction` of the sorted durations are
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
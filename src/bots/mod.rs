@@ -0,0 +1,253 @@
+//! Headless bot clients for load testing a server
+//!
+//! `bots --count N` runs N clients in this process, each with its own client id and UDP
+//! socket, connected to the server from the settings. Each bot drives its character with a
+//! [`BotPolicy`] and its connection statistics are reported when the bots stop.
+use std::f32::consts::TAU;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bevy::app::{App, Plugin, PreUpdate, Update};
+use bevy::diagnostic::{DiagnosticPath, DiagnosticsStore};
+use bevy::log::info;
+use bevy::math::Vec2;
+use bevy::prelude::{
+    in_state, IntoSystemConfigs, Query, Res, ResMut, Resource, Time, Timer, TimerMode, With,
+};
+use clap::ValueEnum;
+use leafwing_input_manager::plugin::InputManagerSystem;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::client::plugin::ClientPlugins;
+use lightyear::client::prediction::diagnostics::PredictionDiagnosticsPlugin;
+use lightyear::prelude::client::{ClientCommands, ClientTransport, NetworkingState, Predicted};
+use lightyear::shared::replication::components::Controlled;
+use lightyear::transport::io::IoDiagnosticsPlugin;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::app::harness::{headless_app, headless_client_config, init_logging};
+use crate::app::settings::build_client_netcode_config;
+use crate::app::shared::FIXED_TIMESTEP_HZ;
+use crate::netcode::netgraph::PingTracker;
+use crate::netcode::protocol::CharacterAction;
+use crate::ZinnobreIronSettings;
+
+/// Client ids handed out to the bots, in order
+const FIRST_BOT_ID: u64 = 10_000;
+/// Frames the bots keep running after disconnecting, to send the disconnect packets
+const DISCONNECT_FRAMES: usize = 8;
+/// How fast the circling bots turn, in radians per second
+const CIRCLE_SPEED: f32 = 1.0;
+/// Chance that a random walking bot stands still instead of picking a direction
+const IDLE_CHANCE: f64 = 0.2;
+/// Chance that a random walking bot jumps when it picks a new direction
+const JUMP_CHANCE: f64 = 0.3;
+/// Chance that a random walking bot sprints when it picks a new direction
+const SPRINT_CHANCE: f64 = 0.3;
+
+/// How the bots move their character
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BotPolicy {
+    /// Walk in a random direction for a random time, sometimes jumping or sprinting
+    #[default]
+    RandomWalk,
+    /// Walk in circles
+    Circle,
+}
+
+#[derive(Resource)]
+struct Bot {
+    policy: BotPolicy,
+    rng: StdRng,
+    movement: Vec2,
+    /// Time until the random walk picks a new direction
+    timer: Timer,
+}
+
+/// Connection statistics accumulated while connected
+#[derive(Resource, Default)]
+struct BotStats {
+    samples: u32,
+    rtt_ms: f64,
+    kilobytes_in: f64,
+    kilobytes_out: f64,
+}
+
+impl BotStats {
+    fn average(&self, total: f64) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        total / self.samples as f64
+    }
+}
+
+struct BotPlugin {
+    policy: BotPolicy,
+    seed: u64,
+}
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Bot {
+            policy: self.policy,
+            rng: StdRng::seed_from_u64(self.seed),
+            movement: Vec2::ZERO,
+            timer: Timer::from_seconds(0.0, TimerMode::Once),
+        });
+        app.init_resource::<BotStats>();
+        app.add_systems(
+            PreUpdate,
+            drive_bot.in_set(InputManagerSystem::ManualControl),
+        );
+        app.add_systems(
+            Update,
+            record_bot_stats.run_if(in_state(NetworkingState::Connected)),
+        );
+    }
+}
+
+/// Run the bots for the given time, then report their statistics
+pub(crate) fn run(
+    settings: &ZinnobreIronSettings,
+    count: usize,
+    policy: BotPolicy,
+    duration: Duration,
+) {
+    init_logging();
+    let server_addr = SocketAddr::new(
+        settings.common.client.server_addr.into(),
+        settings.common.client.server_port,
+    );
+    let mut client_plugin = settings.client_plugin();
+    client_plugin.misprediction_log = None;
+
+    let mut bots: Vec<(u64, App)> = (0..count as u64)
+        .map(|i| {
+            let client_id = FIRST_BOT_ID + i;
+            // Every bot gets its own socket on a port picked by the OS
+            let net_config = build_client_netcode_config(
+                client_id,
+                server_addr,
+                settings.common.client.conditioner.as_ref(),
+                &settings.common.shared,
+                ClientTransport::UdpSocket(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+            );
+            let mut config = headless_client_config(net_config);
+            settings.configure_client(&mut config);
+
+            let mut app = headless_app();
+            app.add_plugins(ClientPlugins { config });
+            app.add_plugins((
+                client_plugin.clone(),
+                settings.shared_plugin(),
+                BotPlugin {
+                    policy,
+                    seed: client_id,
+                },
+            ));
+            app.finish();
+            app.cleanup();
+            (client_id, app)
+        })
+        .collect();
+    info!(count, ?policy, ?server_addr, "Starting bots");
+
+    let frame_duration = Duration::from_secs_f64(1.0 / FIXED_TIMESTEP_HZ);
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        let frame_start = Instant::now();
+        for (_, app) in &mut bots {
+            app.update();
+        }
+        std::thread::sleep(frame_duration.saturating_sub(frame_start.elapsed()));
+    }
+
+    for (_, app) in &mut bots {
+        app.world_mut().commands().disconnect_client();
+        app.world_mut().flush();
+    }
+    for _ in 0..DISCONNECT_FRAMES {
+        for (_, app) in &mut bots {
+            app.update();
+        }
+        std::thread::sleep(frame_duration);
+    }
+
+    for (client_id, app) in &bots {
+        report(*client_id, app);
+    }
+}
+
+fn report(client_id: u64, app: &App) {
+    let world = app.world();
+    let stats = world.resource::<BotStats>();
+    let packet_loss_percent = world.resource::<PingTracker>().packet_loss() * 100.0;
+    let rollbacks = world
+        .resource::<DiagnosticsStore>()
+        .get(&PredictionDiagnosticsPlugin::ROLLBACKS)
+        .and_then(|diagnostic| diagnostic.value())
+        .unwrap_or_default();
+    info!(
+        client_id,
+        rtt_ms = stats.average(stats.rtt_ms),
+        packet_loss_percent,
+        rollbacks,
+        kilobytes_in_per_sec = stats.average(stats.kilobytes_in),
+        kilobytes_out_per_sec = stats.average(stats.kilobytes_out),
+        "Bot statistics"
+    );
+}
+
+fn drive_bot(
+    time: Res<Time>,
+    mut bot: ResMut<Bot>,
+    mut query: Query<&mut ActionState<CharacterAction>, (With<Predicted>, With<Controlled>)>,
+) {
+    let Ok(mut action_state) = query.get_single_mut() else {
+        return;
+    };
+    let bot = &mut *bot;
+    match bot.policy {
+        BotPolicy::Circle => {
+            bot.movement = Vec2::from_angle(time.elapsed_seconds() * CIRCLE_SPEED);
+        }
+        BotPolicy::RandomWalk if bot.timer.tick(time.delta()).finished() => {
+            bot.movement = if bot.rng.gen_bool(IDLE_CHANCE) {
+                Vec2::ZERO
+            } else {
+                Vec2::from_angle(bot.rng.gen_range(0.0..TAU))
+            };
+            for (action, chance) in [
+                (CharacterAction::Jump, JUMP_CHANCE),
+                (CharacterAction::Sprint, SPRINT_CHANCE),
+            ] {
+                if bot.rng.gen_bool(chance) {
+                    action_state.press(&action);
+                } else {
+                    action_state.release(&action);
+                }
+            }
+            bot.timer = Timer::from_seconds(bot.rng.gen_range(0.5..2.0), TimerMode::Once);
+        }
+        BotPolicy::RandomWalk => {}
+    }
+    action_state.set_axis_pair(&CharacterAction::Move, bot.movement);
+}
+
+fn record_bot_stats(
+    mut stats: ResMut<BotStats>,
+    tracker: Res<PingTracker>,
+    diagnostics: Res<DiagnosticsStore>,
+) {
+    let value = |path: &DiagnosticPath| {
+        diagnostics
+            .get(path)
+            .and_then(|diagnostic| diagnostic.value())
+            .unwrap_or_default()
+    };
+    stats.samples += 1;
+    stats.rtt_ms += tracker.rtt_secs() as f64 * 1000.0;
+    stats.kilobytes_in += value(&IoDiagnosticsPlugin::BYTES_IN);
+    stats.kilobytes_out += value(&IoDiagnosticsPlugin::BYTES_OUT);
+}
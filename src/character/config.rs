@@ -0,0 +1,119 @@
+use std::f32::consts::FRAC_PI_4;
+
+use avian3d::prelude::Collider;
+use bevy::asset::ron;
+use bevy::prelude::Resource;
+use serde::{Deserialize, Serialize};
+
+use crate::netcode::hash::stable_hash;
+
+/// Every tunable used by the character controller.
+///
+/// Both the server and the predicting clients read movement values from this resource, so
+/// they must hold identical values for prediction to match the server. The server loads it
+/// from `assets/movement_profile.ron` and sends it to every client when they connect.
+#[derive(Resource, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct MovementConfig {
+    /// Horizontal speed when walking
+    pub walk_speed: f32,
+    /// Horizontal speed when sprinting on the ground
+    pub sprint_speed: f32,
+    /// Horizontal speed when crouching
+    pub crouch_speed: f32,
+    /// Maximum horizontal acceleration when grounded
+    pub ground_acceleration: f32,
+    /// Maximum horizontal acceleration when airborne
+    pub air_acceleration: f32,
+    /// Upwards impulse applied when jumping
+    pub jump_impulse: f32,
+    /// Horizontal speed the character is launched at when dashing
+    pub dash_speed: f32,
+    /// Radius of the character capsule
+    pub capsule_radius: f32,
+    /// Length of the cylindrical part of the capsule when standing
+    pub capsule_height: f32,
+    /// Length of the cylindrical part of the capsule when crouching
+    pub crouch_capsule_height: f32,
+    /// Maximum angle in radians between the ground normal and up for the ground to be walkable
+    pub max_slope_angle: f32,
+    /// How far below the capsule we look for ground to be considered grounded
+    pub ground_check_distance: f32,
+    /// How far below the capsule we look for ground to snap to after leaving it
+    pub snap_distance: f32,
+    /// Tallest ledge the character walks up without jumping
+    pub step_height: f32,
+    /// Ticks after walking off a ledge during which a jump is still allowed
+    pub coyote_ticks: u8,
+    /// Ticks a jump press is remembered while the character is in the air
+    pub jump_buffer_ticks: u8,
+    /// Ticks after a jump during which ground detection and snapping are disabled
+    pub jump_lockout_ticks: u8,
+}
+
+impl Default for MovementConfig {
+    fn default() -> Self {
+        Self {
+            walk_speed: 5.5,
+            sprint_speed: 8.5,
+            crouch_speed: 2.5,
+            ground_acceleration: 20.0,
+            air_acceleration: 5.0,
+            jump_impulse: 5.0,
+            dash_speed: 15.0,
+            capsule_radius: 0.5,
+            capsule_height: 0.5,
+            crouch_capsule_height: 0.1,
+            max_slope_angle: FRAC_PI_4,
+            ground_check_distance: 0.05,
+            snap_distance: 0.3,
+            step_height: 0.3,
+            coyote_ticks: 6,
+            jump_buffer_ticks: 6,
+            jump_lockout_ticks: 4,
+        }
+    }
+}
+
+impl MovementConfig {
+    /// Length of the cylindrical part of the capsule for the given crouch state
+    pub fn capsule_height(&self, crouching: bool) -> f32 {
+        if crouching {
+            self.crouch_capsule_height
+        } else {
+            self.capsule_height
+        }
+    }
+
+    pub fn collider(&self, crouching: bool) -> Collider {
+        Collider::capsule(self.capsule_radius, self.capsule_height(crouching))
+    }
+
+    /// Whether the collider is the capsule [`MovementConfig::collider`] builds for this state
+    pub fn is_collider(&self, collider: &Collider, crouching: bool) -> bool {
+        const TOLERANCE: f32 = 1e-4;
+        collider.shape().as_capsule().is_some_and(|capsule| {
+            (capsule.height() - self.capsule_height(crouching)).abs() < TOLERANCE
+                && (capsule.radius - self.capsule_radius).abs() < TOLERANCE
+        })
+    }
+
+    /// How much the capsule's center moves down when going from standing to crouching
+    /// while keeping the feet on the ground
+    pub fn crouch_offset(&self) -> f32 {
+        (self.capsule_height - self.crouch_capsule_height) / 2.0
+    }
+
+    /// Hash of the serialized profile, used to check that the server and clients agree on it
+    pub fn profile_hash(&self) -> u64 {
+        // f32 isn't Hash, so hash the serialized form instead
+        let serialized = ron::ser::to_string(self).expect("Could not serialize movement profile");
+        stable_hash(serialized.as_bytes())
+    }
+}
+
+/// Parse a movement profile from its RON representation
+pub fn parse_movement_profile(
+    profile_str: &str,
+) -> Result<MovementConfig, ron::error::SpannedError> {
+    ron::de::from_str::<MovementConfig>(profile_str)
+}
@@ -0,0 +1,269 @@
+//! Character controller shared by the server and the predicted clients.
+//!
+//! The character is still a dynamic rigid body (so impulses and contacts with props keep
+//! working), but everything that decides *how* it moves is driven from here: grounded state
+//! from shape casts, slope limits, step-up, ground snapping, coyote time and jump buffering.
+//! All timers are counted in ticks and stored in [`CharacterController`], which is predicted,
+//! so a rollback restores the exact controller state for the tick being replayed.
+pub(crate) mod config;
+
+use avian3d::prelude::{Collider, SpatialQuery, SpatialQueryFilter};
+use bevy::math::{Dir3, Quat, Vec3};
+use bevy::prelude::Component;
+use serde::{Deserialize, Serialize};
+
+use config::MovementConfig;
+
+/// Small offset used so casts don't start inside the surface they are looking for
+const SKIN_WIDTH: f32 = 0.02;
+
+/// Predicted state of the character controller.
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct CharacterController {
+    /// Whether the character is standing on walkable ground this tick
+    pub grounded: bool,
+    /// Normal of the ground the character stands on, `Vec3::Y` when airborne
+    pub ground_normal: Vec3,
+    /// Whether the character's collider is currently the crouching one
+    pub crouching: bool,
+    /// Remaining ticks during which a jump is allowed without being grounded
+    pub coyote_ticks: u8,
+    /// Remaining ticks during which a buffered jump press will be consumed
+    pub jump_buffer_ticks: u8,
+    /// Remaining ticks during which ground detection is suppressed after a jump
+    pub jump_lockout_ticks: u8,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        Self {
+            grounded: false,
+            ground_normal: Vec3::Y,
+            crouching: false,
+            coyote_ticks: 0,
+            jump_buffer_ticks: 0,
+            jump_lockout_ticks: 0,
+        }
+    }
+}
+
+/// Ground found below the character
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GroundHit {
+    /// Distance between the bottom of the capsule and the ground
+    pub(crate) distance: f32,
+    pub(crate) normal: Vec3,
+}
+
+impl GroundHit {
+    pub(crate) fn is_walkable(&self, config: &MovementConfig) -> bool {
+        self.normal.angle_between(Vec3::Y) <= config.max_slope_angle
+    }
+}
+
+impl CharacterController {
+    /// Refresh the grounded state and ground snapping for this tick.
+    ///
+    /// Returns the vertical offset that should be applied to the character's position to
+    /// snap it down onto the ground (zero if no snapping is needed).
+    pub(crate) fn update_ground(
+        &mut self,
+        config: &MovementConfig,
+        spatial_query: &SpatialQuery,
+        position: Vec3,
+        vertical_velocity: f32,
+        filter: &SpatialQueryFilter,
+    ) -> f32 {
+        let was_grounded = self.grounded;
+        self.jump_lockout_ticks = self.jump_lockout_ticks.saturating_sub(1);
+
+        let collider = config.collider(self.crouching);
+        let ground = if self.jump_lockout_ticks == 0 {
+            cast_ground(
+                spatial_query,
+                &collider,
+                position,
+                config.snap_distance,
+                filter,
+            )
+        } else {
+            None
+        }
+        .filter(|hit| hit.is_walkable(config));
+
+        let mut snap = 0.0;
+        self.grounded = match ground {
+            Some(hit) if hit.distance <= config.ground_check_distance => true,
+            // Keep the character glued to the ground when walking down slopes and steps,
+            // but never pull it down while it is moving upwards.
+            Some(hit) if was_grounded && vertical_velocity <= 0.0 => {
+                snap = -hit.distance;
+                true
+            }
+            _ => false,
+        };
+
+        if self.grounded {
+            self.ground_normal = ground.map_or(Vec3::Y, |hit| hit.normal);
+            self.coyote_ticks = config.coyote_ticks;
+        } else {
+            self.ground_normal = Vec3::Y;
+            self.coyote_ticks = self.coyote_ticks.saturating_sub(1);
+        }
+        snap
+    }
+
+    /// Register a jump press and return true if a jump should happen this tick.
+    pub(crate) fn try_jump(&mut self, config: &MovementConfig, jump_just_pressed: bool) -> bool {
+        if jump_just_pressed {
+            self.jump_buffer_ticks = config.jump_buffer_ticks;
+        } else {
+            self.jump_buffer_ticks = self.jump_buffer_ticks.saturating_sub(1);
+        }
+
+        if self.jump_buffer_ticks == 0 || self.coyote_ticks == 0 {
+            return false;
+        }
+
+        self.jump_buffer_ticks = 0;
+        self.coyote_ticks = 0;
+        self.jump_lockout_ticks = config.jump_lockout_ticks;
+        self.grounded = false;
+        self.ground_normal = Vec3::Y;
+        true
+    }
+
+    /// Switch between the standing and crouching collider.
+    ///
+    /// Standing up only happens if the standing capsule fits, so crouching under a low
+    /// ceiling keeps the character crouched. Returns the vertical offset that should be
+    /// applied to the character's position to keep its feet in place, or `None` if the
+    /// crouch state did not change.
+    pub(crate) fn update_crouch(
+        &mut self,
+        config: &MovementConfig,
+        spatial_query: &SpatialQuery,
+        position: Vec3,
+        wants_crouch: bool,
+        filter: &SpatialQueryFilter,
+    ) -> Option<f32> {
+        if wants_crouch == self.crouching {
+            return None;
+        }
+
+        // In the air the legs are pulled up instead, so the center doesn't move
+        let offset = if self.grounded {
+            config.crouch_offset()
+        } else {
+            0.0
+        };
+
+        if wants_crouch {
+            self.crouching = true;
+            return Some(-offset);
+        }
+
+        let standing_position = position + Vec3::Y * offset;
+        let blocked = !spatial_query
+            .shape_intersections(
+                &config.collider(false),
+                standing_position,
+                Quat::IDENTITY,
+                filter.clone(),
+            )
+            .is_empty();
+        if blocked {
+            return None;
+        }
+        self.crouching = false;
+        Some(offset)
+    }
+}
+
+/// Cast the character's capsule straight down to find the ground below it.
+pub(crate) fn cast_ground(
+    spatial_query: &SpatialQuery,
+    collider: &Collider,
+    position: Vec3,
+    max_distance: f32,
+    filter: &SpatialQueryFilter,
+) -> Option<GroundHit> {
+    spatial_query
+        .cast_shape(
+            collider,
+            position + Vec3::Y * SKIN_WIDTH,
+            Quat::IDENTITY,
+            Dir3::NEG_Y,
+            max_distance + SKIN_WIDTH,
+            true,
+            filter.clone(),
+        )
+        .map(|hit| GroundHit {
+            distance: (hit.time_of_impact - SKIN_WIDTH).max(0.0),
+            normal: hit.normal1,
+        })
+}
+
+/// Find how far the character has to be lifted to walk over an obstacle in front of it.
+///
+/// Returns `None` if there is nothing to step over, or if the obstacle is too tall or the
+/// top of it is not walkable.
+pub(crate) fn step_up_height(
+    config: &MovementConfig,
+    spatial_query: &SpatialQuery,
+    collider: &Collider,
+    position: Vec3,
+    horizontal_motion: Vec3,
+    filter: &SpatialQueryFilter,
+) -> Option<f32> {
+    let Ok(direction) = Dir3::new(horizontal_motion) else {
+        return None;
+    };
+    let distance = horizontal_motion.length() + SKIN_WIDTH;
+
+    // Is something blocking us at our current height?
+    let blocking = spatial_query.cast_shape(
+        collider,
+        position + Vec3::Y * SKIN_WIDTH,
+        Quat::IDENTITY,
+        direction,
+        distance,
+        true,
+        filter.clone(),
+    )?;
+    if blocking.normal1.angle_between(Vec3::Y) <= config.max_slope_angle {
+        // Walkable slope, the regular ground handling takes care of it
+        return None;
+    }
+
+    // Is the path clear once raised by the step height?
+    let raised = position + Vec3::Y * config.step_height;
+    if spatial_query
+        .cast_shape(
+            collider,
+            raised,
+            Quat::IDENTITY,
+            direction,
+            distance,
+            true,
+            filter.clone(),
+        )
+        .is_some()
+    {
+        return None;
+    }
+
+    // Find the top of the step from above
+    let ground = cast_ground(
+        spatial_query,
+        collider,
+        raised + direction * distance,
+        config.step_height,
+        filter,
+    )?;
+    if !ground.is_walkable(config) {
+        return None;
+    }
+    let height = config.step_height - ground.distance;
+    (height > SKIN_WIDTH).then_some(height + SKIN_WIDTH)
+}
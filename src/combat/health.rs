@@ -0,0 +1,111 @@
+//! Server-authoritative health, death and respawning.
+use avian3d::prelude::{
+    AngularVelocity, CollisionLayers, LinearVelocity, LockedAxes, Position, RigidBody,
+};
+use bevy::log::info;
+use bevy::math::Vec3;
+use bevy::prelude::{Commands, Entity, EventReader, Has, Query, Res, With, Without};
+use lightyear::prelude::TickManager;
+
+use crate::character::CharacterController;
+use crate::combat::DamageEvent;
+use crate::level::spawn::SpawnPointSelector;
+use crate::netcode::protocol::{CharacterMarker, Dead, Health};
+use crate::team::Team;
+
+/// Health characters spawn with
+pub(crate) const MAX_HEALTH: f32 = 100.0;
+/// Ticks a dead character waits before respawning
+pub(crate) const RESPAWN_TICKS: i16 = 192;
+
+pub(crate) fn apply_damage(
+    mut commands: Commands,
+    tick_manager: Res<TickManager>,
+    mut damage_events: EventReader<DamageEvent>,
+    mut health_query: Query<&mut Health, Without<Dead>>,
+) {
+    for event in damage_events.read() {
+        let Ok(mut health) = health_query.get_mut(event.target) else {
+            continue;
+        };
+        // Several hits can land on the same tick, only the first one kills
+        if health.current <= 0.0 {
+            continue;
+        }
+        health.current = (health.current - event.amount).max(0.0);
+        if health.current <= 0.0 {
+            info!(target = ?event.target, instigator = ?event.instigator, "Character died");
+            commands.entity(event.target).insert(Dead {
+                respawn_tick: tick_manager.tick() + RESPAWN_TICKS,
+            });
+        }
+    }
+}
+
+/// Take dead characters out of the physics world until they respawn, so their invisible bodies
+/// don't block anyone or absorb shots. Runs on the server and on predicting clients.
+pub(crate) fn disable_dead_characters(
+    mut commands: Commands,
+    query: Query<
+        (Entity, Has<Dead>, Option<&CollisionLayers>),
+        (With<CharacterMarker>, With<RigidBody>),
+    >,
+) {
+    for (entity, is_dead, layers) in &query {
+        let disabled = layers == Some(&CollisionLayers::NONE);
+        if is_dead && !disabled {
+            commands
+                .entity(entity)
+                .insert((CollisionLayers::NONE, LockedAxes::ALL_LOCKED));
+        } else if !is_dead && disabled {
+            commands
+                .entity(entity)
+                .insert((CollisionLayers::default(), LockedAxes::ROTATION_LOCKED));
+        }
+    }
+}
+
+pub(crate) fn respawn_characters(
+    mut commands: Commands,
+    tick_manager: Res<TickManager>,
+    spawn_points: SpawnPointSelector,
+    mut dead_query: Query<(
+        Entity,
+        &Dead,
+        &mut Health,
+        &mut Position,
+        &mut LinearVelocity,
+        &mut AngularVelocity,
+        &mut CharacterController,
+        Option<&Team>,
+    )>,
+) {
+    let tick = tick_manager.tick();
+    let mut taken = Vec::new();
+    for (
+        entity,
+        dead,
+        mut health,
+        mut position,
+        mut linear_velocity,
+        mut angular_velocity,
+        mut controller,
+        team,
+    ) in &mut dead_query
+    {
+        if tick - dead.respawn_tick < 0 {
+            continue;
+        }
+        info!(?entity, "Respawning character");
+        health.current = health.max;
+        position.0 = spawn_points.select(team.copied(), Some(entity), &taken);
+        taken.push(position.0);
+        linear_velocity.0 = Vec3::ZERO;
+        angular_velocity.0 = Vec3::ZERO;
+        *controller = CharacterController {
+            crouching: controller.crouching,
+            ..Default::default()
+        };
+        commands.entity(entity).remove::<Dead>();
+    }
+}
@@ -0,0 +1,246 @@
+//! Hitscan weapons with server-side lag compensation.
+//!
+//! Firing is an [`Ability`](crate::netcode::protocol::Ability), so its cooldown is predicted
+//! like any other ability and the shooter sees the muzzle flash and tracer immediately. Hits
+//! are only decided by the server: it keeps a short [`PositionHistory`] of every character,
+//! and ray casts against the other characters as they were at the tick the shooter saw them.
+use std::collections::VecDeque;
+
+use avian3d::prelude::{Collider, Position, SpatialQuery, SpatialQueryFilter};
+use bevy::log::debug;
+use bevy::math::{Dir3, Quat, Vec3};
+use bevy::prelude::{
+    Component, Entity, Event, EventReader, EventWriter, Has, Query, Res, ResMut, Timer, TimerMode,
+    With, Without,
+};
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::{Tick, TickManager};
+use lightyear::server::connection::ConnectionManager;
+use lightyear::server::events::MessageEvent;
+use lightyear::shared::replication::network_target::NetworkTarget;
+
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::combat::DamageEvent;
+use crate::netcode::protocol::{
+    CharacterAction, CharacterMarker, CombatChannel, Dead, PlayerId, ShotMessage, ViewDelayMessage,
+};
+
+/// Maximum distance a hitscan shot can travel
+pub(crate) const HITSCAN_RANGE: f32 = 100.0;
+/// Seconds between two shots
+pub(crate) const FIRE_COOLDOWN_SECS: f32 = 0.2;
+/// Damage dealt to a character hit by a shot
+pub(crate) const HITSCAN_DAMAGE: f32 = 10.0;
+/// How far back in time the server is willing to rewind characters for a shot
+pub(crate) const LAG_COMPENSATION_MAX_TICKS: u16 = 32;
+/// Seconds a tracer stays on screen
+pub(crate) const TRACER_LIFETIME_SECS: f32 = 0.15;
+
+/// Ring buffer of a character's recent positions, kept by the server for lag compensation
+#[derive(Component, Debug, Default)]
+pub struct PositionHistory {
+    buffer: VecDeque<(Tick, Vec3)>,
+}
+
+impl PositionHistory {
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn record(&mut self, tick: Tick, position: Vec3) {
+        if self.buffer.len() >= LAG_COMPENSATION_MAX_TICKS as usize {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back((tick, position));
+    }
+
+    /// Position at the given tick, or at the closest earlier tick we have
+    pub fn get(&self, tick: Tick) -> Option<Vec3> {
+        self.buffer
+            .iter()
+            .rev()
+            .find(|(recorded_tick, _)| tick - *recorded_tick >= 0)
+            .map(|(_, position)| *position)
+    }
+}
+
+/// How many ticks behind its own predicted tick the shooter sees the other characters.
+///
+/// Reported by the client with [`ViewDelayMessage`]; zero while other characters are predicted.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct ShooterViewDelay(pub u16);
+
+/// A shot fired on the server this tick, waiting to be resolved
+#[derive(Event, Debug)]
+pub struct HitscanShot {
+    pub shooter: Entity,
+    pub tick: Tick,
+    pub origin: Vec3,
+    pub direction: Dir3,
+}
+
+/// Short-lived cosmetic line drawn for a shot
+#[derive(Component, Debug)]
+pub struct Tracer {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub hit: bool,
+    pub timer: Timer,
+}
+
+impl Tracer {
+    pub fn new(start: Vec3, end: Vec3, hit: bool) -> Self {
+        Self {
+            start,
+            end,
+            hit,
+            timer: Timer::from_seconds(TRACER_LIFETIME_SECS, TimerMode::Once),
+        }
+    }
+}
+
+/// Where a shot starts and which way it goes, from the character's aim input.
+pub fn shot_ray(
+    config: &MovementConfig,
+    controller: &CharacterController,
+    position: Vec3,
+    action_state: &ActionState<CharacterAction>,
+) -> (Vec3, Dir3) {
+    let aim = action_state.axis_pair(&CharacterAction::Aim);
+    let direction = Dir3::new(Vec3::new(aim.x, 0.0, aim.y)).unwrap_or(Dir3::Z);
+    let origin = position + Vec3::Y * config.capsule_height(controller.crouching) / 2.0;
+    (origin, direction)
+}
+
+/// Ray cast a shot against the current world, returning where it stopped and what it hit.
+///
+/// Used by the shooting client for its predicted tracer.
+pub fn cast_shot(
+    spatial_query: &SpatialQuery,
+    origin: Vec3,
+    direction: Dir3,
+    shooter: Entity,
+) -> (Vec3, Option<Entity>) {
+    match spatial_query.cast_ray(
+        origin,
+        direction,
+        HITSCAN_RANGE,
+        true,
+        SpatialQueryFilter::from_excluded_entities([shooter]),
+    ) {
+        Some(hit) => (origin + direction * hit.time_of_impact, Some(hit.entity)),
+        None => (origin + direction * HITSCAN_RANGE, None),
+    }
+}
+
+/// Record where the characters are at the start of the tick, before any input or physics.
+///
+/// That's the world a client shooting on this tick ray casts against, and the one the shots
+/// resolved later in the tick must be rewound to.
+pub(crate) fn record_position_history(
+    tick_manager: Res<TickManager>,
+    mut query: Query<(&Position, &mut PositionHistory, Has<Dead>)>,
+) {
+    let tick = tick_manager.tick();
+    for (position, mut history, is_dead) in &mut query {
+        // Dead characters can't be hit, not even by shots aimed before they died
+        if is_dead {
+            history.clear();
+        } else {
+            history.record(tick, position.0);
+        }
+    }
+}
+
+pub(crate) fn receive_view_delay(
+    mut events: EventReader<MessageEvent<ViewDelayMessage>>,
+    mut query: Query<(&PlayerId, &mut ShooterViewDelay)>,
+) {
+    for event in events.read() {
+        let client_id = *event.context();
+        let ticks = event.message().ticks.min(LAG_COMPENSATION_MAX_TICKS);
+        for (player_id, mut view_delay) in &mut query {
+            if player_id.0 == client_id {
+                view_delay.0 = ticks;
+            }
+        }
+    }
+}
+
+/// Resolve this tick's shots against the other characters rewound to what each shooter saw
+pub(crate) fn resolve_hitscan_shots(
+    mut shots: EventReader<HitscanShot>,
+    spatial_query: SpatialQuery,
+    mut connection: ResMut<ConnectionManager>,
+    mut damage_events: EventWriter<DamageEvent>,
+    shooters: Query<(&PlayerId, &ShooterViewDelay)>,
+    characters: Query<
+        (Entity, &PositionHistory, &Collider),
+        (With<CharacterMarker>, Without<Dead>),
+    >,
+) {
+    if shots.is_empty() {
+        return;
+    }
+    let character_entities: Vec<Entity> = characters.iter().map(|(entity, ..)| entity).collect();
+
+    for shot in shots.read() {
+        let Ok((shooter_id, view_delay)) = shooters.get(shot.shooter) else {
+            continue;
+        };
+        let view_tick = shot.tick - view_delay.0 as i16;
+
+        // Static geometry and props are not rewound
+        let mut distance = spatial_query
+            .cast_ray(
+                shot.origin,
+                shot.direction,
+                HITSCAN_RANGE,
+                true,
+                SpatialQueryFilter::from_excluded_entities(character_entities.iter().copied()),
+            )
+            .map_or(HITSCAN_RANGE, |hit| hit.time_of_impact);
+
+        let mut hit_entity = None;
+        for (entity, history, collider) in &characters {
+            if entity == shot.shooter {
+                continue;
+            }
+            let Some(rewound_position) = history.get(view_tick) else {
+                continue;
+            };
+            if let Some((time_of_impact, _)) = collider.cast_ray(
+                rewound_position,
+                Quat::IDENTITY,
+                shot.origin,
+                *shot.direction,
+                distance,
+                true,
+            ) {
+                distance = time_of_impact;
+                hit_entity = Some(entity);
+            }
+        }
+
+        debug!(?shot, ?view_tick, ?hit_entity, "Resolved hitscan shot");
+        if let Some(target) = hit_entity {
+            damage_events.send(DamageEvent {
+                target,
+                amount: HITSCAN_DAMAGE,
+                instigator: Some(shooter_id.0),
+            });
+        }
+        connection
+            .send_message_to_target::<CombatChannel, _>(
+                &mut ShotMessage {
+                    shooter: shooter_id.0,
+                    origin: shot.origin,
+                    end: shot.origin + shot.direction * distance,
+                    hit: hit_entity.is_some(),
+                },
+                NetworkTarget::All,
+            )
+            .unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+//! Weapons and damage.
+pub(crate) mod health;
+pub(crate) mod hitscan;
+pub(crate) mod projectile;
+
+use bevy::prelude::{Entity, Event};
+use lightyear::prelude::ClientId;
+
+/// Damage dealt to an entity, only ever sent on the server
+#[derive(Event, Debug, Clone, Copy)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub amount: f32,
+    /// The player who dealt the damage, if any
+    pub instigator: Option<ClientId>,
+}
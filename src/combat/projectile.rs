@@ -0,0 +1,156 @@
+//! Physics projectiles spawned with prediction.
+//!
+//! The server spawns the authoritative projectile and replicates it. The shooting client
+//! spawns its own copy on the same tick as a [`PreSpawnedPlayerObject`] with the same hash,
+//! so it appears instantly and lightyear swaps it for the server's entity once that arrives.
+
+use avian3d::prelude::{
+    Collider, CollidingEntities, GravityScale, LinearVelocity, Position, RigidBody,
+};
+use bevy::math::{Dir3, Vec3};
+use bevy::prelude::{Bundle, Commands, Entity, EventWriter, Name, Query, Res, With};
+use lightyear::prelude::client::PreSpawnedPlayerObject;
+use lightyear::prelude::{ClientId, Tick, TickManager};
+
+use crate::combat::DamageEvent;
+use crate::netcode::hash::stable_hash;
+use crate::netcode::protocol::{CharacterMarker, PlayerId, Projectile};
+
+/// Radius of the projectile's sphere collider
+pub(crate) const PROJECTILE_RADIUS: f32 = 0.15;
+/// Speed the projectile is launched at
+pub(crate) const PROJECTILE_SPEED: f32 = 20.0;
+/// Ticks before a projectile that hit nothing despawns
+pub(crate) const PROJECTILE_LIFETIME_TICKS: i16 = 192;
+/// Damage dealt to a character hit by a projectile
+pub(crate) const PROJECTILE_DAMAGE: f32 = 30.0;
+/// Seconds between two projectiles
+pub(crate) const PROJECTILE_COOLDOWN_SECS: f32 = 0.8;
+
+#[derive(Bundle)]
+pub(crate) struct ProjectilePhysicsBundle {
+    collider: Collider,
+    rigid_body: RigidBody,
+    gravity_scale: GravityScale,
+    colliding_entities: CollidingEntities,
+}
+
+impl Default for ProjectilePhysicsBundle {
+    fn default() -> Self {
+        Self {
+            collider: Collider::sphere(PROJECTILE_RADIUS),
+            rigid_body: RigidBody::Dynamic,
+            gravity_scale: GravityScale(0.0),
+            colliding_entities: CollidingEntities::default(),
+        }
+    }
+}
+
+/// Everything the server and the shooting client both spawn for a new projectile
+#[derive(Bundle)]
+pub(crate) struct ProjectileBundle {
+    name: Name,
+    projectile: Projectile,
+    position: Position,
+    linear_velocity: LinearVelocity,
+    physics: ProjectilePhysicsBundle,
+    pre_spawned: PreSpawnedPlayerObject,
+}
+
+impl ProjectileBundle {
+    /// `origin` is the center of the shooter, the projectile starts just outside of it
+    pub(crate) fn new(
+        shooter: ClientId,
+        tick: Tick,
+        origin: Vec3,
+        direction: Dir3,
+        shooter_radius: f32,
+    ) -> Self {
+        let start = origin + direction * (shooter_radius + PROJECTILE_RADIUS + 0.05);
+        Self {
+            name: Name::new("Projectile"),
+            projectile: Projectile {
+                shooter,
+                spawn_tick: tick,
+            },
+            position: Position(start),
+            linear_velocity: LinearVelocity(direction * PROJECTILE_SPEED),
+            physics: ProjectilePhysicsBundle::default(),
+            pre_spawned: PreSpawnedPlayerObject::new(projectile_hash(shooter, tick)),
+        }
+    }
+}
+
+/// Hash identifying a projectile on both the server and the shooting client.
+///
+/// A player can fire at most one projectile per tick, so the shooter and tick are enough.
+pub(crate) fn projectile_hash(shooter: ClientId, tick: Tick) -> u64 {
+    let mut bytes = [0; 10];
+    bytes[..8].copy_from_slice(&shooter.to_bits().to_le_bytes());
+    bytes[8..].copy_from_slice(&tick.0.to_le_bytes());
+    stable_hash(&bytes)
+}
+
+/// What a projectile should do this tick
+pub(crate) enum ProjectileOutcome {
+    /// Keep flying
+    Alive,
+    /// Its lifetime ran out
+    Expired,
+    /// It touched something other than its shooter; the characters it touched are listed
+    Hit(Vec<Entity>),
+}
+
+/// The shooter's own character is ignored: the projectile starts right next to it, and
+/// would otherwise die without doing anything when the shooter runs into it
+pub(crate) fn projectile_outcome(
+    tick: Tick,
+    projectile: &Projectile,
+    colliding_entities: &CollidingEntities,
+    characters: &Query<&PlayerId, With<CharacterMarker>>,
+) -> ProjectileOutcome {
+    let mut hit = colliding_entities
+        .iter()
+        .copied()
+        .filter(|entity| {
+            characters
+                .get(*entity)
+                .map_or(true, |player_id| player_id.0 != projectile.shooter)
+        })
+        .peekable();
+    if hit.peek().is_some() {
+        let hit_characters = hit.filter(|entity| characters.contains(*entity)).collect();
+        return ProjectileOutcome::Hit(hit_characters);
+    }
+    if tick - projectile.spawn_tick >= PROJECTILE_LIFETIME_TICKS {
+        return ProjectileOutcome::Expired;
+    }
+    ProjectileOutcome::Alive
+}
+
+/// Despawn projectiles that hit something or expired, damaging the characters they hit
+pub(crate) fn handle_projectiles(
+    mut commands: Commands,
+    tick_manager: Res<TickManager>,
+    mut damage_events: EventWriter<DamageEvent>,
+    projectile_query: Query<(Entity, &Projectile, &CollidingEntities)>,
+    characters: Query<&PlayerId, With<CharacterMarker>>,
+) {
+    let tick = tick_manager.tick();
+    for (entity, projectile, colliding_entities) in &projectile_query {
+        match projectile_outcome(tick, projectile, colliding_entities, &characters) {
+            ProjectileOutcome::Alive => continue,
+            ProjectileOutcome::Expired => {}
+            ProjectileOutcome::Hit(targets) => {
+                for target in targets {
+                    damage_events.send(DamageEvent {
+                        target,
+                        amount: PROJECTILE_DAMAGE,
+                        instigator: Some(projectile.shooter),
+                    });
+                }
+            }
+        }
+        commands.entity(entity).despawn();
+    }
+}
@@ -1,7 +1,4 @@
-use avian3d::{
-    math::Scalar,
-    prelude::{LinearVelocity, ShapeHits},
-};
+use avian3d::prelude::LinearVelocity;
 use bevy::{
     ecs::query::QueryData,
     prelude::{Component, Query, Transform},
@@ -14,6 +11,8 @@ use leafwing_input_manager::prelude::{
 };
 use lightyear::prelude::{Deserialize, Serialize};
 
+use crate::character::config::MovementConfig;
+
 #[derive(Actionlike, PartialEq, Eq, Clone, Copy, Hash, Debug, Reflect, Serialize, Deserialize)]
 pub enum BasicAction {
     #[actionlike(DualAxis)]
@@ -43,16 +42,10 @@ pub(crate) struct MovementQuery {
     transform: &'static Transform,
 }
 
-#[derive(QueryData)]
-#[query_data(derive(Debug))]
-pub(crate) struct GroundQuery {
-    ground_hits: Option<&'static ShapeHits>,
-    grounded: Option<&'static Grounded>,
-}
-
 //TODO: maybe I don't need dt here if I'm doing instant accel like overwatch?
 pub(crate) fn sys_movement(
     // time: Res<Time>,
+    movement_config: &MovementConfig,
     action_state: &ActionState<BasicAction>,
     mut mov_query: Query<MovementQuery>,
 ) {
@@ -63,27 +56,14 @@ pub(crate) fn sys_movement(
         .clamp_length_max(1.0);
 
     for mut mov in &mut mov_query {
-        let vel = mov.basic_mov.move_speed;
+        let vel = movement_config.walk_speed;
         let wish_mov = move_dir * vel;
         mov.lin_vel.x = wish_mov.x;
         mov.lin_vel.z = wish_mov.y;
     }
 }
 
-#[derive(Component, Reflect, Debug)]
-pub(crate) struct Grounded;
-
-#[derive(Component, Reflect, Debug)]
+#[derive(Component, Reflect, Debug, Default)]
 pub(crate) struct BasicMovement {
-    pub(crate) move_speed: Scalar,
     pub(crate) ground_tick: u8,
 }
-
-impl Default for BasicMovement {
-    fn default() -> Self {
-        Self {
-            move_speed: 5.5,
-            ground_tick: 0,
-        }
-    }
-}
@@ -0,0 +1,155 @@
+//! Static world data: where things are placed in the level.
+//!
+//! Levels are RON files in `assets/levels`. The server loads one and tells the clients its
+//! name; everyone then spawns the static geometry and lights locally. Only the dynamic props
+//! are replicated, since they can move.
+pub(crate) mod rotation;
+pub(crate) mod spawn;
+
+use std::fs;
+
+use avian3d::prelude::{Collider, Position, RigidBody};
+use bevy::asset::ron;
+use bevy::core::Name;
+use bevy::log::{error, info, warn};
+use bevy::math::Vec3;
+use bevy::prelude::{Color, Commands, Component, Entity, Query, Resource, With};
+use serde::{Deserialize, Serialize};
+
+use crate::netcode::hash::stable_hash;
+use crate::prop::Prop;
+use spawn::{SpawnPoint, SpawnPolicy};
+
+/// Directory the level files are read from
+pub(crate) const LEVELS_DIR: &str = "assets/levels";
+/// Level loaded when nothing else is configured, also embedded in the binary
+pub(crate) const DEFAULT_LEVEL: &str = "arena";
+
+/// Everything placed in a level, as stored in its file
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LevelDefinition {
+    pub name: String,
+    /// Immovable geometry, spawned locally by the server and every client
+    pub static_colliders: Vec<LevelGeometry>,
+    /// Dynamic props, spawned and replicated by the server
+    pub props: Vec<PropDefinition>,
+    pub spawn_points: Vec<SpawnPoint>,
+    #[serde(default)]
+    pub spawn_policy: SpawnPolicy,
+    pub lights: Vec<LevelLight>,
+}
+
+/// A static box in the level
+#[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LevelGeometry {
+    /// Center of the box
+    pub position: Vec3,
+    /// Full extents of the box
+    pub size: Vec3,
+    /// sRGB color
+    pub color: [f32; 3],
+}
+
+impl LevelGeometry {
+    pub fn color(&self) -> Color {
+        Color::srgb(self.color[0], self.color[1], self.color[2])
+    }
+}
+
+/// A dynamic prop in the level
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct PropDefinition {
+    pub position: Vec3,
+    pub prop: Prop,
+}
+
+/// A point light in the level
+#[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LevelLight {
+    pub position: Vec3,
+    /// Luminous power in lumens
+    pub intensity: f32,
+    pub shadows: bool,
+}
+
+impl LevelDefinition {
+    /// Hash of the serialized level, used to check that the server and clients agree on it
+    pub fn level_hash(&self) -> u64 {
+        let serialized = ron::ser::to_string(self).expect("Could not serialize level");
+        stable_hash(serialized.as_bytes())
+    }
+}
+
+/// The level currently loaded
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct CurrentLevel(pub LevelDefinition);
+
+/// Marks every entity spawned from a level, so the level can be unloaded
+#[derive(Component, Clone, Copy, Debug)]
+pub struct LevelEntity;
+
+/// Parse a level from its RON representation
+pub fn parse_level(level_str: &str) -> Result<LevelDefinition, ron::error::SpannedError> {
+    ron::de::from_str::<LevelDefinition>(level_str)
+}
+
+/// Read and parse the level with the given name from [`LEVELS_DIR`].
+///
+/// The default level falls back to the copy embedded in the binary if it can't be read.
+pub(crate) fn load_level(name: &str) -> Option<LevelDefinition> {
+    let path = format!("{LEVELS_DIR}/{name}.ron");
+    let level_str = match fs::read_to_string(&path) {
+        Ok(level_str) => level_str,
+        Err(e) if name == DEFAULT_LEVEL => {
+            warn!("Could not read {path} ({e}), using the embedded level");
+            include_str!("../../assets/levels/arena.ron").to_string()
+        }
+        Err(e) => {
+            error!("Could not read level {path}: {e}");
+            return None;
+        }
+    };
+    match parse_level(&level_str) {
+        Ok(level) => {
+            for (index, prop) in level.props.iter().enumerate() {
+                if let Err(e) = prop.prop.validate() {
+                    error!("Invalid prop {index} in level {path}: {e}");
+                    return None;
+                }
+            }
+            info!(name, hash = level.level_hash(), "Loaded level");
+            Some(level)
+        }
+        Err(e) => {
+            error!("Could not parse level {path}: {e}");
+            None
+        }
+    }
+}
+
+/// Spawn the parts of the level that every peer creates locally
+pub(crate) fn spawn_level_geometry(commands: &mut Commands, level: &LevelDefinition) {
+    for geometry in &level.static_colliders {
+        commands.spawn((
+            Name::new("LevelGeometry"),
+            LevelEntity,
+            Position(geometry.position),
+            Collider::cuboid(geometry.size.x, geometry.size.y, geometry.size.z),
+            RigidBody::Static,
+            geometry.clone(),
+        ));
+    }
+    for light in &level.lights {
+        commands.spawn((Name::new("LevelLight"), LevelEntity, light.clone()));
+    }
+}
+
+/// Despawn every entity that was spawned from the current level
+pub(crate) fn despawn_level(
+    commands: &mut Commands,
+    level_query: &Query<Entity, With<LevelEntity>>,
+) {
+    for entity in level_query {
+        commands.entity(entity).despawn();
+    }
+}
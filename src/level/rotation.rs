@@ -0,0 +1,70 @@
+//! Switching the server to another level without restarting it.
+use bevy::log::info;
+use bevy::prelude::{Event, EventReader, EventWriter, Query, ResMut, Resource};
+use bevy::utils::HashSet;
+use lightyear::prelude::ClientId;
+use lightyear::server::events::MessageEvent;
+
+use crate::netcode::protocol::{MapVoteMessage, PlayerId};
+
+/// Levels the server cycles through, by name
+#[derive(Resource, Clone, Debug)]
+pub(crate) struct MapRotation {
+    levels: Vec<String>,
+    current: usize,
+}
+
+impl MapRotation {
+    pub(crate) fn new(levels: Vec<String>) -> Self {
+        Self { levels, current: 0 }
+    }
+
+    /// Name of the level currently selected in the rotation, if the rotation isn't empty
+    pub(crate) fn current(&self) -> Option<&str> {
+        self.levels.get(self.current).map(String::as_str)
+    }
+
+    /// Move to the next level in the rotation and return its name
+    pub(crate) fn advance(&mut self) -> Option<&str> {
+        if self.levels.is_empty() {
+            return None;
+        }
+        self.current = (self.current + 1) % self.levels.len();
+        self.current()
+    }
+}
+
+/// Ends the current round and loads the next level in the rotation
+#[derive(Event, Clone, Copy, Debug)]
+pub(crate) struct ChangeLevel;
+
+/// Clients that voted to move on to the next level
+#[derive(Resource, Default, Debug)]
+pub(crate) struct MapVotes(pub(crate) HashSet<ClientId>);
+
+/// Count map votes and change the level once more than half of the players voted
+pub(crate) fn receive_map_votes(
+    mut events: EventReader<MessageEvent<MapVoteMessage>>,
+    mut votes: ResMut<MapVotes>,
+    mut change_level: EventWriter<ChangeLevel>,
+    player_query: Query<&PlayerId>,
+) {
+    for event in events.read() {
+        let client_id = *event.context();
+        if votes.0.insert(client_id) {
+            info!(?client_id, "Client voted to change the level");
+        }
+    }
+    // Forget the votes of clients that left
+    votes.0.retain(|client_id| {
+        player_query
+            .iter()
+            .any(|player_id| player_id.0 == *client_id)
+    });
+
+    let players = player_query.iter().count();
+    if players > 0 && votes.0.len() * 2 > players {
+        votes.0.clear();
+        change_level.send(ChangeLevel);
+    }
+}
@@ -0,0 +1,123 @@
+//! Where characters appear when they join or respawn.
+use avian3d::prelude::{Position, SpatialQuery, SpatialQueryFilter};
+use bevy::ecs::system::SystemParam;
+use bevy::log::warn;
+use bevy::math::{Quat, Vec3};
+use bevy::prelude::{Component, Entity, Query, Res, Resource, With, Without};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::character::config::MovementConfig;
+use crate::netcode::protocol::{CharacterMarker, Dead};
+use crate::team::Team;
+
+/// A place where characters can spawn. Only exists on the server.
+#[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SpawnPoint {
+    /// Where the center of the character is placed
+    pub position: Vec3,
+    /// If set, only characters of this team spawn here under [`SpawnPolicy::Team`]
+    pub team: Option<Team>,
+}
+
+/// How the server picks a spawn point among the free ones
+#[derive(Resource, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SpawnPolicy {
+    /// Any free spawn point
+    Random,
+    /// The free spawn point whose closest enemy is the farthest away
+    #[default]
+    FarthestFromEnemies,
+    /// A free spawn point of the character's team (or a neutral one), farthest from enemies
+    Team,
+}
+
+/// Picks spawn points for new and respawning characters
+#[derive(SystemParam)]
+pub(crate) struct SpawnPointSelector<'w, 's> {
+    policy: Res<'w, SpawnPolicy>,
+    movement_config: Res<'w, MovementConfig>,
+    spatial_query: SpatialQuery<'w, 's>,
+    spawn_points: Query<'w, 's, &'static SpawnPoint>,
+    characters: Query<
+        'w,
+        's,
+        (Entity, &'static Position, Option<&'static Team>),
+        (With<CharacterMarker>, Without<Dead>),
+    >,
+}
+
+impl SpawnPointSelector<'_, '_> {
+    /// Choose where to spawn a character of the given team.
+    ///
+    /// `spawning` is the character being (re)spawned, if it already exists, so that it is
+    /// neither considered an enemy nor an obstacle. `taken` are the points already chosen in
+    /// this system run: those characters are only moved once its commands are applied, so
+    /// neither the overlap check nor the enemy positions can see them yet.
+    pub(crate) fn select(
+        &self,
+        team: Option<Team>,
+        spawning: Option<Entity>,
+        taken: &[Vec3],
+    ) -> Vec3 {
+        let collider = self.movement_config.collider(false);
+        let filter =
+            SpatialQueryFilter::from_excluded_entities(spawning.into_iter().collect::<Vec<_>>());
+
+        let enemies: Vec<Vec3> = self
+            .characters
+            .iter()
+            .filter(|(entity, _, _)| Some(*entity) != spawning)
+            .filter(|(_, _, other_team)| team.is_none() || other_team.copied() != team)
+            .map(|(_, position, _)| position.0)
+            .collect();
+
+        let all_points: Vec<&SpawnPoint> = self.spawn_points.iter().collect();
+        let Some(fallback) = all_points.first() else {
+            warn!("No spawn points in the level, spawning at the origin");
+            return Vec3::Y * 3.0;
+        };
+
+        let candidates: Vec<&SpawnPoint> = all_points
+            .iter()
+            .copied()
+            .filter(|point| {
+                *self.policy != SpawnPolicy::Team || point.team.is_none() || point.team == team
+            })
+            .filter(|point| !taken.contains(&point.position))
+            .filter(|point| {
+                self.spatial_query
+                    .shape_intersections(&collider, point.position, Quat::IDENTITY, filter.clone())
+                    .is_empty()
+            })
+            .collect();
+
+        let chosen = match *self.policy {
+            SpawnPolicy::Random => candidates.choose(&mut rand::thread_rng()).copied(),
+            SpawnPolicy::FarthestFromEnemies | SpawnPolicy::Team => {
+                candidates.iter().copied().max_by(|a, b| {
+                    closest_distance(a.position, &enemies)
+                        .total_cmp(&closest_distance(b.position, &enemies))
+                })
+            }
+        };
+        chosen
+            .unwrap_or_else(|| {
+                warn!("Every spawn point is blocked, spawning on an occupied one");
+                // Still avoid the points given out in this run, everyone would land there
+                all_points
+                    .iter()
+                    .copied()
+                    .find(|point| !taken.contains(&point.position))
+                    .unwrap_or(*fallback)
+            })
+            .position
+    }
+}
+
+fn closest_distance(point: Vec3, others: &[Vec3]) -> f32 {
+    others
+        .iter()
+        .map(|other| point.distance_squared(*other))
+        .fold(f32::INFINITY, f32::min)
+}
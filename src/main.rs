@@ -1,32 +1,71 @@
 use app::settings::{read_settings, Settings};
 use app::{Apps, Cli};
+use bevy::log::error;
+use lightyear::client::config::ClientConfig;
 use netcode::client::ZinnobreIronClientPlugin;
+use netcode::interest::InterestSettings;
+use netcode::remote_input::RemoteInputPolicy;
+use netcode::replication::ReplicationModes;
 use netcode::server::ZinnobreIronServerPlugin;
 use netcode::shared::SharedPlugin;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
+mod abilities;
 mod app;
+mod benchmark;
+mod bots;
+mod character;
+mod combat;
 mod input;
+mod level;
 mod netcode;
+mod prop;
 mod render;
+mod replay;
+#[cfg(test)]
+mod scenarios;
+mod team;
 
 fn main() {
     let cli = Cli::default();
     let settings_str = include_str!("../assets/settings.ron");
     let settings = read_settings::<ZinnobreIronSettings>(settings_str);
-    let mut apps = Apps::new(settings.common, cli);
-    apps.update_lightyear_client_config(|config| {
-        config.prediction.minimum_input_delay_ticks = settings.input_delay_ticks;
-        config.prediction.correction_ticks_factor = settings.correction_ticks_factor;
-    })
-    .add_lightyear_plugins()
-    .add_user_plugins(
-        ZinnobreIronClientPlugin,
-        ZinnobreIronServerPlugin,
-        SharedPlugin,
-    );
-
-    apps.run();
+    match cli {
+        Cli::Bots {
+            count,
+            seconds,
+            policy,
+        } => bots::run(&settings, count, policy, Duration::from_secs(seconds)),
+        Cli::Benchmark {
+            max_players,
+            step,
+            ticks,
+            output,
+        } => {
+            if let Err(err) = benchmark::run(&settings, max_players, step, ticks, &output) {
+                error!("Benchmark failed: {err:#}");
+                std::process::exit(1);
+            }
+        }
+        Cli::Replay { file } => {
+            if let Err(err) = replay::run(&settings, &file) {
+                error!("Replay failed: {err:#}");
+                std::process::exit(1);
+            }
+        }
+        Cli::Game(mode) => {
+            let mut apps = Apps::new(settings.common.clone(), mode);
+            apps.update_lightyear_client_config(|config| settings.configure_client(config))
+                .add_lightyear_plugins()
+                .add_user_plugins(
+                    settings.client_plugin(),
+                    settings.server_plugin(),
+                    settings.shared_plugin(),
+                );
+            apps.run();
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -36,4 +75,60 @@ pub struct ZinnobreIronSettings {
     pub(crate) input_delay_ticks: u16,
 
     pub(crate) correction_ticks_factor: f32,
+
+    /// How clients guess the inputs of other players they predict
+    pub(crate) remote_input_policy: RemoteInputPolicy,
+
+    /// Which entities clients predict and which they interpolate
+    pub(crate) replication_modes: ReplicationModes,
+
+    /// Draw the server's version of predicted entities, toggled with F3
+    pub(crate) show_confirmed: bool,
+
+    /// CSV file the client writes every misprediction to
+    #[serde(default)]
+    pub(crate) misprediction_log: Option<String>,
+
+    /// Distance-based replication of characters and props
+    pub(crate) interest: InterestSettings,
+
+    /// Levels the server cycles through when the round ends
+    pub(crate) map_rotation: Vec<String>,
+
+    /// File the server records every applied input to, to replay the session with `replay`
+    #[serde(default)]
+    pub(crate) input_recording: Option<String>,
+}
+
+impl ZinnobreIronSettings {
+    pub(crate) fn configure_client(&self, config: &mut ClientConfig) {
+        config.prediction.minimum_input_delay_ticks = self.input_delay_ticks;
+        config.prediction.correction_ticks_factor = self.correction_ticks_factor;
+    }
+
+    pub(crate) fn client_plugin(&self) -> ZinnobreIronClientPlugin {
+        ZinnobreIronClientPlugin {
+            remote_input_policy: self.remote_input_policy,
+            misprediction_log: self.misprediction_log.clone(),
+        }
+    }
+
+    pub(crate) fn server_plugin(&self) -> ZinnobreIronServerPlugin {
+        ZinnobreIronServerPlugin {
+            interest: self.interest,
+            map_rotation: self.map_rotation.clone(),
+            input_recording: self.input_recording.clone(),
+        }
+    }
+
+    pub(crate) fn shared_plugin(&self) -> SharedPlugin {
+        let mut replication_modes = self.replication_modes;
+        replication_modes.characters = self
+            .remote_input_policy
+            .character_replication(replication_modes.characters);
+        SharedPlugin {
+            replication_modes,
+            show_confirmed: self.show_confirmed,
+        }
+    }
 }
@@ -1,27 +1,48 @@
+use avian3d::prelude::CollidingEntities;
+use avian3d::prelude::Position;
+use avian3d::prelude::RigidBody;
 use avian3d::prelude::SpatialQuery;
 use bevy::color::Color;
 use bevy::log::info;
+use bevy::log::warn;
 use bevy::prelude::default;
 use bevy::prelude::not;
 use bevy::prelude::Added;
+use bevy::prelude::ButtonInput;
+use bevy::prelude::Camera;
 use bevy::prelude::Entity;
+use bevy::prelude::GlobalTransform;
 use bevy::prelude::Has;
+use bevy::prelude::InfinitePlane3d;
 use bevy::prelude::IntoSystemConfigs;
 use bevy::prelude::KeyCode;
+use bevy::prelude::Local;
+use bevy::prelude::MouseButton;
+use bevy::prelude::Or;
 use bevy::prelude::TextBundle;
+use bevy::prelude::Vec2;
+use bevy::prelude::Vec3;
+use bevy::prelude::Window;
+use bevy::prelude::Without;
 use bevy::text::TextStyle;
+use bevy::window::PrimaryWindow;
 use bevy::{
-    app::{FixedUpdate, Plugin, PreUpdate, Startup, Update},
-    prelude::{Commands, EventReader, Query, Res, With},
+    app::{FixedUpdate, Last, Plugin, PreUpdate, Startup, Update},
+    prelude::{Commands, EventReader, Query, Res, ResMut, With},
     time::Time,
 };
+use leafwing_input_manager::plugin::InputManagerSystem;
 use leafwing_input_manager::prelude::ActionState;
 use leafwing_input_manager::prelude::InputMap;
 use leafwing_input_manager::prelude::KeyboardVirtualDPad;
+use lightyear::client::connection::ConnectionManager;
 use lightyear::client::events::ConnectEvent;
+use lightyear::client::events::MessageEvent;
 use lightyear::prelude::client::ClientConnection;
+use lightyear::prelude::client::InterpolateStatus;
+use lightyear::prelude::client::Interpolated;
 use lightyear::prelude::client::NetClient;
-use lightyear::prelude::Replicated;
+use lightyear::prelude::client::PredictionDespawnCommandsExt;
 use lightyear::shared::replication::components::Controlled;
 use lightyear::{
     inputs::leafwing::input_buffer::InputBuffer,
@@ -31,13 +52,57 @@ use lightyear::{
     },
 };
 
+use crate::abilities::{apply_ability_action, AbilityQuery};
+use crate::app::line_log::flush_on_exit;
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::combat::hitscan::{cast_shot, shot_ray, Tracer};
+use crate::combat::projectile::{
+    projectile_outcome, ProjectileBundle, ProjectileOutcome, ProjectilePhysicsBundle,
+};
+use crate::level::{despawn_level, load_level, spawn_level_geometry, CurrentLevel, LevelEntity};
+use crate::netcode::mispredictions::{
+    add_physics_history, clean_misprediction_stats, detect_mispredictions, record_physics_history,
+    MispredictionLog, MispredictionStats,
+};
+use crate::netcode::netgraph::{
+    count_received_messages, receive_pongs, sample_netgraph, send_pings, ChannelBytes, NetGraph,
+    PingTracker,
+};
 use crate::netcode::protocol::*;
+use crate::netcode::remote_input::RemoteInputPolicy;
+use crate::netcode::replication::ReplicationModes;
 use crate::netcode::shared::*;
+use crate::prop::interact::handle_interactions;
+use crate::prop::{Prop, PropPhysicsBundle};
 
-pub struct ZinnobreIronClientPlugin;
+#[derive(Clone)]
+pub struct ZinnobreIronClientPlugin {
+    pub remote_input_policy: RemoteInputPolicy,
+    /// CSV file to write every detected misprediction to
+    pub misprediction_log: Option<String>,
+}
 
 impl Plugin for ZinnobreIronClientPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
+        app.insert_resource(self.remote_input_policy);
+        app.init_resource::<MispredictionStats>();
+        app.init_resource::<NetGraph>();
+        app.init_resource::<PingTracker>();
+        app.init_resource::<ChannelBytes>();
+        app.insert_resource(
+            self.misprediction_log
+                .as_deref()
+                .map(MispredictionLog::create)
+                .unwrap_or_default(),
+        );
+        app.add_systems(
+            FixedUpdate,
+            record_physics_history
+                .after(FixedSet::Physics)
+                .run_if(not(is_host_server)),
+        );
+        app.add_systems(Last, flush_on_exit::<MispredictionLog>);
         app.add_systems(Startup, connect_to_server);
         app.add_systems(
             PreUpdate,
@@ -47,19 +112,53 @@ impl Plugin for ZinnobreIronClientPlugin {
         );
         app.add_systems(
             FixedUpdate,
-            handle_character_actions
+            (
+                handle_character_actions,
+                handle_ability_actions,
+                handle_interactions::<With<Predicted>>,
+                handle_predicted_projectiles,
+            )
+                .chain()
                 .run_if(not(is_host_server))
                 .in_set(FixedSet::Main),
         );
+        app.add_systems(
+            PreUpdate,
+            update_aim.in_set(InputManagerSystem::ManualControl),
+        );
         app.add_systems(
             Update,
-            (handle_new_floor, handle_new_block, handle_new_character),
+            (
+                receive_movement_profile,
+                receive_level.run_if(not(is_host_server)),
+                spawn_remote_tracers,
+                send_view_delay,
+                send_map_vote,
+                (
+                    add_physics_history,
+                    detect_mispredictions,
+                    clean_misprediction_stats,
+                ),
+                (
+                    send_pings,
+                    receive_pongs,
+                    count_received_messages,
+                    sample_netgraph,
+                )
+                    .chain()
+                    .run_if(not(is_host_server)),
+                handle_new_prop,
+                handle_new_character,
+                handle_new_projectile,
+            ),
         );
     }
 }
 
 fn handle_character_actions(
     time: Res<Time>,
+    movement_config: Res<MovementConfig>,
+    remote_input_policy: Res<RemoteInputPolicy>,
     spatial_query: SpatialQuery,
     mut query: Query<
         (
@@ -67,7 +166,7 @@ fn handle_character_actions(
             &InputBuffer<CharacterAction>,
             CharacterQuery,
         ),
-        With<Predicted>,
+        (With<Predicted>, Without<Dead>),
     >,
     tick_manager: Res<TickManager>,
     rollback: Option<Res<Rollback>>,
@@ -78,15 +177,220 @@ fn handle_character_actions(
         .unwrap_or(tick_manager.tick());
 
     for (action_state, input_buffer, mut character) in &mut query {
-        if input_buffer.get(tick).is_some() {
-            apply_character_action(&time, &spatial_query, action_state, &mut character);
+        // Our own inputs are always buffered; a remote player's input for this tick may not
+        // have arrived yet, in which case the policy guesses it from the last one we got.
+        let extrapolated;
+        let action_state = match input_buffer.get_last_with_tick() {
+            Some((last_tick, last)) if input_buffer.get(tick).is_none() => {
+                extrapolated = remote_input_policy.extrapolate(last, tick - last_tick);
+                &extrapolated
+            }
+            _ => action_state,
+        };
+        apply_character_action(
+            &time,
+            &movement_config,
+            &spatial_query,
+            action_state,
+            &mut character,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_ability_actions(
+    mut commands: Commands,
+    time: Res<Time>,
+    movement_config: Res<MovementConfig>,
+    replication_modes: Res<ReplicationModes>,
+    spatial_query: SpatialQuery,
+    mut query: Query<
+        (
+            &ActionState<Ability>,
+            &InputBuffer<Ability>,
+            &ActionState<CharacterAction>,
+            &PlayerId,
+            AbilityQuery,
+            CharacterQuery,
+            Has<Controlled>,
+        ),
+        (With<Predicted>, Without<Dead>),
+    >,
+    tick_manager: Res<TickManager>,
+    rollback: Option<Res<Rollback>>,
+) {
+    let tick = rollback
+        .as_ref()
+        .map(|rb| tick_manager.tick_or_rollback_tick(rb))
+        .unwrap_or(tick_manager.tick());
+
+    let is_rollback = rollback.as_ref().is_some_and(|rb| rb.is_rollback());
+
+    let no_input = ActionState::<Ability>::default();
+    for (
+        action_state,
+        input_buffer,
+        character_action_state,
+        player_id,
+        mut abilities,
+        mut character,
+        is_controlled,
+    ) in &mut query
+    {
+        // Unlike movement, we never extrapolate a missing ability input: repeating the last
+        // just_pressed would activate the ability again every tick. Cooldowns still tick.
+        let action_state = if input_buffer.get(tick).is_some() {
+            action_state
+        } else {
+            &no_input
+        };
+        let activated = apply_ability_action(
+            &time,
+            &movement_config,
+            action_state,
+            character_action_state,
+            &mut abilities,
+            &mut character,
+        );
+
+        // Predicted effects, only the first time this tick is simulated
+        if !is_controlled || is_rollback {
             continue;
         }
+        let (origin, direction) = shot_ray(
+            &movement_config,
+            &character.controller,
+            character.position.0,
+            character_action_state,
+        );
+        if activated.contains(&Ability::Fire) {
+            let (end, _) = cast_shot(&spatial_query, origin, direction, character.entity);
+            commands.spawn(Tracer::new(origin, end, false));
+        }
+        // The server's projectile only replaces our pre-spawned one if we predict it
+        if activated.contains(&Ability::Projectile)
+            && replication_modes.projectiles.owner_predicts()
+        {
+            commands.spawn(ProjectileBundle::new(
+                player_id.0,
+                tick,
+                origin,
+                direction,
+                movement_config.capsule_radius,
+            ));
+        }
+    }
+}
 
-        if let Some((_, prev_action_state)) = input_buffer.get_last_with_tick() {
-            apply_character_action(&time, &spatial_query, prev_action_state, &mut character);
-        } else {
-            apply_character_action(&time, &spatial_query, action_state, &mut character);
+/// Predict projectiles despawning when they hit something or expire
+fn handle_predicted_projectiles(
+    mut commands: Commands,
+    tick_manager: Res<TickManager>,
+    rollback: Option<Res<Rollback>>,
+    projectile_query: Query<(Entity, &Projectile, &CollidingEntities)>,
+    characters: Query<&PlayerId, With<CharacterMarker>>,
+) {
+    let tick = rollback
+        .as_ref()
+        .map(|rb| tick_manager.tick_or_rollback_tick(rb))
+        .unwrap_or(tick_manager.tick());
+
+    for (entity, projectile, colliding_entities) in &projectile_query {
+        if !matches!(
+            projectile_outcome(tick, projectile, colliding_entities, &characters),
+            ProjectileOutcome::Alive
+        ) {
+            commands.entity(entity).prediction_despawn();
+        }
+    }
+}
+
+/// Aim where the cursor points on the horizontal plane going through the character
+fn update_aim(
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    mut character_query: Query<
+        (&Position, &mut ActionState<CharacterAction>),
+        (With<Predicted>, With<Controlled>),
+    >,
+) {
+    let Ok((position, mut action_state)) = character_query.get_single_mut() else {
+        return;
+    };
+    let (Ok(window), Ok((camera, camera_transform))) =
+        (window_query.get_single(), camera_query.get_single())
+    else {
+        return;
+    };
+    let Some(ray) = window
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+    else {
+        return;
+    };
+    let Some(distance) = ray.intersect_plane(position.0, InfinitePlane3d::new(Vec3::Y)) else {
+        return;
+    };
+    let aim = (ray.get_point(distance) - position.0).normalize_or_zero();
+    action_state.set_axis_pair(&CharacterAction::Aim, Vec2::new(aim.x, aim.z));
+}
+
+fn spawn_remote_tracers(
+    mut commands: Commands,
+    mut events: EventReader<MessageEvent<ShotMessage>>,
+    connection: Res<ClientConnection>,
+) {
+    let client_id = connection.id();
+    for event in events.read() {
+        let shot = event.message();
+        // Our own shots already have a predicted tracer, only show them again on a hit
+        if shot.shooter != client_id || shot.hit {
+            commands.spawn(Tracer::new(shot.origin, shot.end, shot.hit));
+        }
+    }
+}
+
+/// Tell the server how far in the past we display the other characters, for lag compensation
+fn send_view_delay(
+    tick_manager: Res<TickManager>,
+    interpolated_query: Query<&InterpolateStatus<Position>, With<CharacterMarker>>,
+    mut connection: ResMut<ConnectionManager>,
+    mut channel_bytes: ResMut<ChannelBytes>,
+    mut last_sent: Local<Option<u16>>,
+) {
+    let tick = tick_manager.tick();
+    let ticks = interpolated_query
+        .iter()
+        .map(|status| (tick - status.current_tick).max(0) as u16)
+        .max()
+        .unwrap_or(0);
+    // Interpolation jitters by a tick from frame to frame, don't send for that
+    if last_sent.is_some_and(|last| last.abs_diff(ticks) <= 1) {
+        return;
+    }
+    let message = ViewDelayMessage { ticks };
+    if connection
+        .send_message::<CombatChannel, _>(&message)
+        .is_ok()
+    {
+        channel_bytes.record_sent::<CombatChannel, _>(&message);
+        *last_sent = Some(ticks);
+    }
+}
+
+/// Vote to move on to the next level in the server's rotation
+fn send_map_vote(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut connection: ResMut<ConnectionManager>,
+    mut channel_bytes: ResMut<ChannelBytes>,
+) {
+    if keys.just_pressed(KeyCode::F5) {
+        info!("Voting to change the level");
+        if connection
+            .send_message::<ConfigChannel, _>(&MapVoteMessage)
+            .is_ok()
+        {
+            channel_bytes.record_sent::<ConfigChannel, _>(&MapVoteMessage);
         }
     }
 }
@@ -112,55 +416,151 @@ pub(crate) fn handle_connection(
     }
 }
 
+fn receive_movement_profile(
+    mut events: EventReader<MessageEvent<MovementProfileMessage>>,
+    mut movement_config: ResMut<MovementConfig>,
+) {
+    for event in events.read() {
+        let message = event.message();
+        let local_hash = movement_config.profile_hash();
+        if local_hash == message.hash {
+            info!(hash = message.hash, "Movement profile matches the server's");
+            continue;
+        }
+        // Anything predicted with our profile until now may diverge from the server
+        warn!(
+            hash = message.hash,
+            local_hash, "Movement profile differs from the server's, switching to the server's"
+        );
+        *movement_config = message.config.clone();
+    }
+}
+
+/// Load the level the server is running and spawn its geometry locally
+fn receive_level(
+    mut commands: Commands,
+    mut events: EventReader<MessageEvent<LevelMessage>>,
+    current_level: Option<Res<CurrentLevel>>,
+    level_query: Query<Entity, With<LevelEntity>>,
+) {
+    for event in events.read() {
+        let message = event.message();
+        if current_level
+            .as_ref()
+            .is_some_and(|current| current.0.level_hash() == message.hash)
+        {
+            continue;
+        }
+        let Some(level) = load_level(&message.name) else {
+            warn!(
+                name = message.name,
+                "Could not load the level the server is running"
+            );
+            continue;
+        };
+        if level.level_hash() != message.hash {
+            warn!(
+                name = message.name,
+                hash = message.hash,
+                "Level hash mismatch, the local level file differs from the server's"
+            );
+        }
+        info!(name = message.name, "Loading level");
+        despawn_level(&mut commands, &level_query);
+        spawn_level_geometry(&mut commands, &level);
+        commands.insert_resource(CurrentLevel(level));
+    }
+}
+
 fn handle_new_character(
     connection: Res<ClientConnection>,
+    window_query: Query<(), With<PrimaryWindow>>,
+    movement_config: Res<MovementConfig>,
     mut commands: Commands,
     mut character_query: Query<
-        (Entity, &ColorComponent, Has<Controlled>),
-        (Added<Predicted>, With<CharacterMarker>),
+        (
+            Entity,
+            &CharacterController,
+            Has<Controlled>,
+            Has<Predicted>,
+        ),
+        (
+            Or<(Added<Predicted>, Added<Interpolated>)>,
+            With<CharacterMarker>,
+        ),
     >,
 ) {
-    for (entity, color, is_controlled) in &mut character_query {
-        if is_controlled {
+    for (entity, controller, is_controlled, is_predicted) in &mut character_query {
+        if !is_predicted {
+            // Interpolated characters are moved by the server's updates, they only need a
+            // collider so that we bump into them and our shots can hit them
+            info!(?entity, "Remote character interpolated for us");
+            commands.entity(entity).insert((
+                movement_config.collider(controller.crouching),
+                RigidBody::Kinematic,
+            ));
+            continue;
+        }
+        if is_controlled && window_query.is_empty() {
+            // Headless, there is no device to read: whoever runs us drives the ActionStates
+            // directly. The empty maps still mark the inputs as ours to send to the server.
+            info!("Adding empty InputMap to headless controlled entity {entity:?}");
+            commands.entity(entity).insert((
+                InputMap::<CharacterAction>::default(),
+                InputMap::<Ability>::default(),
+            ));
+        } else if is_controlled {
             info!("Adding InputMap to controlled and predicted entity {entity:?}");
             // TODO: refactor to input module
-            commands.entity(entity).insert(
-                InputMap::new([(CharacterAction::Jump, KeyCode::Space)])
-                    .with_dual_axis(CharacterAction::Move, KeyboardVirtualDPad::WASD),
-            );
+            commands.entity(entity).insert((
+                InputMap::new([
+                    (CharacterAction::Jump, KeyCode::Space),
+                    (CharacterAction::Sprint, KeyCode::ShiftLeft),
+                    (CharacterAction::Crouch, KeyCode::ControlLeft),
+                    (CharacterAction::Interact, KeyCode::KeyE),
+                ])
+                .with_dual_axis(CharacterAction::Move, KeyboardVirtualDPad::WASD),
+                InputMap::new([(Ability::Dash, KeyCode::KeyQ)])
+                    .with(Ability::Fire, MouseButton::Left)
+                    .with(Ability::Projectile, MouseButton::Right),
+            ));
         } else {
             info!("Remote character replicated to us: {entity:?}");
         }
         let client_id = connection.id();
         info!(?entity, ?client_id, "Adding physics to character");
-        commands
-            .entity(entity)
-            .insert((CharacterPhysicsBundle::default(),));
+        commands.entity(entity).insert((CharacterPhysicsBundle::new(
+            &movement_config,
+            controller.crouching,
+        ),));
     }
 }
 
-fn handle_new_floor(
-    _connection: Res<ClientConnection>,
+fn handle_new_projectile(
     mut commands: Commands,
-    character_query: Query<Entity, (Added<Replicated>, With<FloorMarker>)>,
+    // Projectiles we fired ourselves were pre-spawned with their physics already
+    projectile_query: Query<Entity, (Added<Predicted>, With<Projectile>, Without<RigidBody>)>,
 ) {
-    for entity in &character_query {
-        info!(?entity, "Adding physics to floor");
+    for entity in &projectile_query {
         commands
             .entity(entity)
-            .insert(FloorPhysicsBundle::default());
+            .insert(ProjectilePhysicsBundle::default());
     }
 }
 
-fn handle_new_block(
-    _connection: Res<ClientConnection>,
+fn handle_new_prop(
     mut commands: Commands,
-    character_query: Query<Entity, (Added<Predicted>, With<BlockMarker>)>,
+    prop_query: Query<(Entity, &Prop, Has<Predicted>), Or<(Added<Predicted>, Added<Interpolated>)>>,
 ) {
-    for entity in &character_query {
-        info!(?entity, "Adding physics to block");
-        commands
-            .entity(entity)
-            .insert(BlockPhysicsBundle::default());
+    for (entity, prop, is_predicted) in &prop_query {
+        info!(?entity, "Adding physics to prop");
+        if is_predicted {
+            commands.entity(entity).insert(PropPhysicsBundle::new(prop));
+        } else {
+            // Interpolated props follow the server, we only collide with them
+            commands
+                .entity(entity)
+                .insert((prop.collider(), RigidBody::Kinematic));
+        }
     }
 }
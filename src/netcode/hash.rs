@@ -0,0 +1,14 @@
+//! Hashes the server and clients compare with each other.
+//!
+//! `DefaultHasher` is only guaranteed to be stable within one build, so a server and a client
+//! built with different toolchains could disagree on it. FNV-1a is fixed.
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a hash of the bytes
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
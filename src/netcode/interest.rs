@@ -0,0 +1,116 @@
+//! Distance-based interest management.
+//!
+//! Entities replicated with [`NetworkRelevanceMode::InterestManagement`] are only sent to the
+//! clients whose character is close to them. Level geometry keeps the default mode and is
+//! always replicated to everyone.
+use avian3d::prelude::Position;
+use bevy::ecs::entity::EntityHashSet;
+use bevy::log::debug;
+use bevy::prelude::{Entity, Query, Res, ResMut, Resource, Time, Timer, TimerMode, With};
+use bevy::utils::HashMap;
+use lightyear::prelude::server::{NetworkRelevanceMode, RelevanceManager};
+use lightyear::prelude::{ClientId, TickManager};
+use serde::{Deserialize, Serialize};
+
+use crate::netcode::protocol::{CharacterMarker, PlayerId};
+
+#[derive(Resource, Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct InterestSettings {
+    /// Entities closer than this to a client's character are replicated to that client
+    pub radius: f32,
+    /// How much further than `radius` an entity has to go before it stops being replicated,
+    /// so that entities on the edge don't keep appearing and disappearing
+    pub hysteresis: f32,
+}
+
+/// Interest-managed entities currently relevant to each client
+#[derive(Resource, Default)]
+pub(crate) struct RelevantEntities(HashMap<ClientId, EntityHashSet>);
+
+/// Timer for the periodic relevance statistics log
+#[derive(Resource)]
+pub(crate) struct RelevanceStatsTimer(pub(crate) Timer);
+
+impl Default for RelevanceStatsTimer {
+    fn default() -> Self {
+        Self(Timer::from_seconds(5.0, TimerMode::Repeating))
+    }
+}
+
+/// Ticks between two relevance updates. Checking every client against every entity is
+/// quadratic in the player count, and entities don't move far in a few ticks
+const RELEVANCE_INTERVAL_TICKS: u16 = 8;
+
+pub(crate) fn relevance_update_due(tick_manager: Res<TickManager>) -> bool {
+    tick_manager.tick().0 % RELEVANCE_INTERVAL_TICKS == 0
+}
+
+pub(crate) fn update_relevance(
+    settings: Res<InterestSettings>,
+    mut relevance_manager: ResMut<RelevanceManager>,
+    mut relevant_entities: ResMut<RelevantEntities>,
+    viewer_query: Query<(Entity, &PlayerId, &Position), With<CharacterMarker>>,
+    entity_query: Query<(Entity, &Position, &NetworkRelevanceMode)>,
+) {
+    let enter_distance_squared = settings.radius.powi(2);
+    let exit_distance_squared = (settings.radius + settings.hysteresis).powi(2);
+
+    relevant_entities.0.retain(|client_id, _| {
+        viewer_query
+            .iter()
+            .any(|(_, player_id, _)| player_id.0 == *client_id)
+    });
+
+    for (viewer, player_id, viewer_position) in &viewer_query {
+        let relevant = relevant_entities.0.entry(player_id.0).or_default();
+        // Forget entities that were despawned
+        relevant.retain(|entity| entity_query.contains(*entity));
+
+        for (entity, position, mode) in &entity_query {
+            if !matches!(mode, NetworkRelevanceMode::InterestManagement) {
+                continue;
+            }
+            let was_relevant = relevant.contains(&entity);
+            let distance_squared = viewer_position.0.distance_squared(position.0);
+            // A client always receives its own character
+            let is_relevant = entity == viewer
+                || if was_relevant {
+                    distance_squared <= exit_distance_squared
+                } else {
+                    distance_squared <= enter_distance_squared
+                };
+
+            if is_relevant && !was_relevant {
+                relevance_manager.gain_relevance(player_id.0, entity);
+                relevant.insert(entity);
+            } else if !is_relevant && was_relevant {
+                relevance_manager.lose_relevance(player_id.0, entity);
+                relevant.remove(&entity);
+            }
+        }
+    }
+}
+
+/// Periodically log how many of the interest-managed entities each client receives
+pub(crate) fn log_relevance_stats(
+    time: Res<Time>,
+    mut timer: ResMut<RelevanceStatsTimer>,
+    relevant_entities: Res<RelevantEntities>,
+    entity_query: Query<&NetworkRelevanceMode>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let total = entity_query
+        .iter()
+        .filter(|mode| matches!(mode, NetworkRelevanceMode::InterestManagement))
+        .count();
+    for (client_id, relevant) in &relevant_entities.0 {
+        debug!(
+            ?client_id,
+            relevant = relevant.len(),
+            total,
+            "Interest-managed entities replicated"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Per-entity, per-component misprediction diagnostics.
+//!
+//! Lightyear only tells us that a rollback happened. To find out which entity and which
+//! physics component diverged, every predicted entity keeps a short history of its physics
+//! state per tick. When the server's state for a tick arrives on the Confirmed entity, it is
+//! compared with what we predicted for that same tick and any difference is recorded, both
+//! in [`MispredictionStats`] (shown on screen) and optionally in a CSV file.
+use std::collections::VecDeque;
+use std::fmt;
+
+use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+use bevy::log::info;
+use bevy::math::{Quat, Vec3};
+use bevy::prelude::{Added, Commands, Component, Entity, Query, Ref, Res, ResMut, Resource, With};
+use bevy::utils::HashMap;
+use lightyear::prelude::client::{Confirmed, Predicted, Rollback};
+use lightyear::prelude::{Tick, TickManager};
+
+use crate::app::line_log::LineLog;
+
+/// Ticks of predicted state kept per entity, must cover the round trip time
+const HISTORY_TICKS: usize = 128;
+/// Differences below this are float noise, not mispredictions
+const ERROR_EPSILON: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum PhysicsComponent {
+    Position,
+    Rotation,
+    LinearVelocity,
+    AngularVelocity,
+}
+
+impl fmt::Display for PhysicsComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PhysicsSnapshot {
+    position: Vec3,
+    rotation: Quat,
+    linear_velocity: Vec3,
+    angular_velocity: Vec3,
+}
+
+impl PhysicsSnapshot {
+    /// Error of each component between two snapshots; angles in radians
+    fn errors(&self, other: &PhysicsSnapshot) -> [(PhysicsComponent, f32); 4] {
+        [
+            (
+                PhysicsComponent::Position,
+                self.position.distance(other.position),
+            ),
+            (
+                PhysicsComponent::Rotation,
+                self.rotation.angle_between(other.rotation),
+            ),
+            (
+                PhysicsComponent::LinearVelocity,
+                self.linear_velocity.distance(other.linear_velocity),
+            ),
+            (
+                PhysicsComponent::AngularVelocity,
+                self.angular_velocity.distance(other.angular_velocity),
+            ),
+        ]
+    }
+}
+
+/// Physics state we predicted for the last ticks
+#[derive(Component, Default)]
+pub(crate) struct PredictedPhysicsHistory(VecDeque<(Tick, PhysicsSnapshot)>);
+
+impl PredictedPhysicsHistory {
+    fn record(&mut self, tick: Tick, snapshot: PhysicsSnapshot) {
+        // A rollback re-simulates ticks we already recorded, the new values replace them
+        while self.0.back().is_some_and(|(last, _)| *last - tick >= 0) {
+            self.0.pop_back();
+        }
+        if self.0.len() == HISTORY_TICKS {
+            self.0.pop_front();
+        }
+        self.0.push_back((tick, snapshot));
+    }
+
+    fn get(&self, tick: Tick) -> Option<&PhysicsSnapshot> {
+        self.0
+            .iter()
+            .find(|(recorded, _)| *recorded == tick)
+            .map(|(_, snapshot)| snapshot)
+    }
+}
+
+/// Accumulated error of one component of one entity
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ComponentErrorStats {
+    pub(crate) count: u32,
+    pub(crate) max: f32,
+    pub(crate) last: f32,
+}
+
+#[derive(Resource, Default, Debug)]
+pub(crate) struct MispredictionStats(
+    pub(crate) HashMap<(Entity, PhysicsComponent), ComponentErrorStats>,
+);
+
+impl MispredictionStats {
+    /// The entries with the most mispredictions first
+    pub(crate) fn worst(
+        &self,
+        count: usize,
+    ) -> Vec<(Entity, PhysicsComponent, ComponentErrorStats)> {
+        let mut entries: Vec<_> = self
+            .0
+            .iter()
+            .map(|((entity, component), stats)| (*entity, *component, *stats))
+            .collect();
+        entries.sort_by(|a, b| b.2.count.cmp(&a.2.count));
+        entries.truncate(count);
+        entries
+    }
+}
+
+/// CSV file every misprediction is written to, if enabled in the settings
+#[derive(Resource, Default)]
+pub(crate) struct MispredictionLog(LineLog);
+
+impl MispredictionLog {
+    pub(crate) fn create(path: &str) -> Self {
+        let log = LineLog::create("misprediction log", path, "tick,entity,component,error");
+        if log.is_open() {
+            info!("Logging mispredictions to {path}");
+        }
+        Self(log)
+    }
+
+    fn write(&mut self, tick: Tick, entity: Entity, component: PhysicsComponent, error: f32) {
+        self.0
+            .write_line(format_args!("{},{entity},{component},{error}", tick.0));
+    }
+}
+
+impl AsMut<LineLog> for MispredictionLog {
+    fn as_mut(&mut self) -> &mut LineLog {
+        &mut self.0
+    }
+}
+
+pub(crate) fn add_physics_history(
+    mut commands: Commands,
+    query: Query<Entity, (Added<Predicted>, With<Position>)>,
+) {
+    for entity in &query {
+        commands
+            .entity(entity)
+            .insert(PredictedPhysicsHistory::default());
+    }
+}
+
+/// Record the state of predicted entities after the physics step, including during rollbacks
+pub(crate) fn record_physics_history(
+    tick_manager: Res<TickManager>,
+    rollback: Option<Res<Rollback>>,
+    mut query: Query<
+        (
+            &Position,
+            &Rotation,
+            &LinearVelocity,
+            &AngularVelocity,
+            &mut PredictedPhysicsHistory,
+        ),
+        With<Predicted>,
+    >,
+) {
+    let tick = rollback
+        .as_ref()
+        .map(|rb| tick_manager.tick_or_rollback_tick(rb))
+        .unwrap_or(tick_manager.tick());
+    for (position, rotation, linear_velocity, angular_velocity, mut history) in &mut query {
+        history.record(
+            tick,
+            PhysicsSnapshot {
+                position: position.0,
+                rotation: rotation.0,
+                linear_velocity: linear_velocity.0,
+                angular_velocity: angular_velocity.0,
+            },
+        );
+    }
+}
+
+/// Compare each server update with what we predicted for the same tick
+pub(crate) fn detect_mispredictions(
+    mut stats: ResMut<MispredictionStats>,
+    mut log: ResMut<MispredictionLog>,
+    confirmed_query: Query<(
+        &Confirmed,
+        Ref<Position>,
+        Ref<Rotation>,
+        Ref<LinearVelocity>,
+        Ref<AngularVelocity>,
+    )>,
+    history_query: Query<&PredictedPhysicsHistory>,
+) {
+    for (confirmed, position, rotation, linear_velocity, angular_velocity) in &confirmed_query {
+        let changed = position.is_changed()
+            || rotation.is_changed()
+            || linear_velocity.is_changed()
+            || angular_velocity.is_changed();
+        let Some(predicted) = confirmed.predicted.filter(|_| changed) else {
+            continue;
+        };
+        let Some(predicted_snapshot) = history_query
+            .get(predicted)
+            .ok()
+            .and_then(|history| history.get(confirmed.tick))
+        else {
+            continue;
+        };
+        let confirmed_snapshot = PhysicsSnapshot {
+            position: position.0,
+            rotation: rotation.0,
+            linear_velocity: linear_velocity.0,
+            angular_velocity: angular_velocity.0,
+        };
+        for (component, error) in confirmed_snapshot.errors(predicted_snapshot) {
+            if error <= ERROR_EPSILON {
+                continue;
+            }
+            let entry = stats.0.entry((predicted, component)).or_default();
+            entry.count += 1;
+            entry.max = entry.max.max(error);
+            entry.last = error;
+            log.write(confirmed.tick, predicted, component, error);
+        }
+    }
+}
+
+/// Forget despawned entities
+pub(crate) fn clean_misprediction_stats(
+    mut stats: ResMut<MispredictionStats>,
+    entity_query: Query<(), With<PredictedPhysicsHistory>>,
+) {
+    stats
+        .0
+        .retain(|(entity, _), _| entity_query.contains(*entity));
+}
@@ -1,4 +1,11 @@
 pub(crate) mod client;
+pub(crate) mod hash;
+pub(crate) mod interest;
+pub(crate) mod mispredictions;
+pub(crate) mod netgraph;
 pub(crate) mod protocol;
+pub(crate) mod recording;
+pub(crate) mod remote_input;
+pub(crate) mod replication;
 pub(crate) mod server;
 pub(crate) mod shared;
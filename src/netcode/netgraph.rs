@@ -0,0 +1,352 @@
+//! Samples the connection statistics plotted by the netgraph overlay.
+//!
+//! Round trip time, jitter and packet loss come from our own pings on an unreliable channel
+//! rather than from lightyear's internal ping, so loss is measured the same way as gameplay
+//! packets experience it.
+//!
+//! lightyear only measures the bandwidth of the whole transport. The bytes of our own messages
+//! are counted per channel by [`ChannelBytes`]; what the totals carry on top of them is the
+//! replication and the inputs, which lightyear sends by itself.
+use std::collections::VecDeque;
+
+use bevy::diagnostic::{DiagnosticPath, DiagnosticsStore};
+use bevy::prelude::{EventReader, Query, Res, ResMut, Resource, Time, Timer, TimerMode, With};
+use bevy::utils::HashMap;
+use lightyear::client::connection::ConnectionManager;
+use lightyear::client::events::MessageEvent;
+use lightyear::inputs::leafwing::input_buffer::InputBuffer;
+use lightyear::prelude::client::{Confirmed, Predicted};
+use lightyear::prelude::{Channel, TickManager};
+use lightyear::server::connection::ConnectionManager as ServerConnectionManager;
+use lightyear::server::events::MessageEvent as ServerMessageEvent;
+use lightyear::shared::replication::components::Controlled;
+use lightyear::shared::replication::network_target::NetworkTarget;
+use lightyear::transport::io::IoDiagnosticsPlugin;
+use serde::Serialize;
+
+use crate::netcode::protocol::{
+    CharacterAction, CombatChannel, ConfigChannel, LevelMessage, MovementProfileMessage,
+    PingChannel, PingMessage, ShotMessage,
+};
+
+/// Number of samples kept per series
+pub(crate) const NETGRAPH_SAMPLES: usize = 60;
+/// Seconds between two samples, so the graphs cover the last 6 seconds
+const SAMPLE_INTERVAL_SECS: f32 = 0.1;
+const PING_INTERVAL_SECS: f32 = 0.25;
+/// A ping unanswered for this long is counted as lost
+const PING_TIMEOUT_SECS: f64 = 2.0;
+/// Number of pings the packet loss is computed over
+const LOSS_WINDOW: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum NetGraphSeries {
+    RoundTripTime,
+    Jitter,
+    PacketLoss,
+    BytesIn,
+    BytesOut,
+    TickOffset,
+    InputDelay,
+}
+
+impl NetGraphSeries {
+    pub(crate) const ALL: [NetGraphSeries; 7] = [
+        NetGraphSeries::RoundTripTime,
+        NetGraphSeries::Jitter,
+        NetGraphSeries::PacketLoss,
+        NetGraphSeries::BytesIn,
+        NetGraphSeries::BytesOut,
+        NetGraphSeries::TickOffset,
+        NetGraphSeries::InputDelay,
+    ];
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            NetGraphSeries::RoundTripTime => "rtt ms",
+            NetGraphSeries::Jitter => "jitter ms",
+            NetGraphSeries::PacketLoss => "loss %",
+            NetGraphSeries::BytesIn => "KB/s in",
+            NetGraphSeries::BytesOut => "KB/s out",
+            NetGraphSeries::TickOffset => "ticks ahead of server",
+            NetGraphSeries::InputDelay => "input delay ticks",
+        }
+    }
+}
+
+/// Last samples of every series, oldest first
+#[derive(Resource)]
+pub(crate) struct NetGraph {
+    pub(crate) series: HashMap<NetGraphSeries, VecDeque<f32>>,
+    /// KB/s received on each channel at the last sample
+    pub(crate) channels_in: Vec<(&'static str, f32)>,
+    /// KB/s sent on each channel at the last sample
+    pub(crate) channels_out: Vec<(&'static str, f32)>,
+    timer: Timer,
+}
+
+impl Default for NetGraph {
+    fn default() -> Self {
+        Self {
+            series: HashMap::default(),
+            channels_in: Vec::new(),
+            channels_out: Vec::new(),
+            timer: Timer::from_seconds(SAMPLE_INTERVAL_SECS, TimerMode::Repeating),
+        }
+    }
+}
+
+impl NetGraph {
+    fn push(&mut self, series: NetGraphSeries, value: f32) {
+        let samples = self.series.entry(series).or_default();
+        if samples.len() == NETGRAPH_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(value);
+    }
+}
+
+/// Bytes of our own messages sent and received on each channel since the last sample.
+///
+/// Messages are sized with the encoding lightyear serializes them with, packet headers aside.
+#[derive(Resource, Default)]
+pub(crate) struct ChannelBytes {
+    sent: HashMap<&'static str, usize>,
+    received: HashMap<&'static str, usize>,
+}
+
+impl ChannelBytes {
+    pub(crate) fn record_sent<C: Channel, M: Serialize>(&mut self, message: &M) {
+        *self.sent.entry(channel_name::<C>()).or_default() += message_size(message);
+    }
+
+    fn record_received<C: Channel, M: Serialize>(&mut self, message: &M) {
+        *self.received.entry(channel_name::<C>()).or_default() += message_size(message);
+    }
+}
+
+/// `PingChannel` is shown as `Ping`
+fn channel_name<C: Channel>() -> &'static str {
+    let name = std::any::type_name::<C>();
+    let name = name.rsplit("::").next().unwrap_or(name);
+    name.strip_suffix("Channel").unwrap_or(name)
+}
+
+fn message_size<M: Serialize>(message: &M) -> usize {
+    bincode::serde::encode_to_vec(message, bincode::config::standard())
+        .map_or(0, |bytes| bytes.len())
+}
+
+/// KB/s of each channel over the last sample interval, after what the total carries on top of
+/// them. The counters are reset for the next interval.
+fn channel_rates(
+    bytes: &mut HashMap<&'static str, usize>,
+    interval_secs: f32,
+    total: f32,
+    rest: &'static str,
+) -> Vec<(&'static str, f32)> {
+    let mut rates: Vec<(&'static str, f32)> = bytes
+        .iter_mut()
+        .map(|(name, bytes)| {
+            let rate = std::mem::take(bytes) as f32 / interval_secs / 1000.0;
+            (*name, rate)
+        })
+        .collect();
+    rates.sort_by_key(|(name, _)| *name);
+    let counted: f32 = rates.iter().map(|(_, rate)| rate).sum();
+    rates.insert(0, (rest, (total - counted).max(0.0)));
+    rates
+}
+
+#[derive(Resource)]
+pub(crate) struct PingTracker {
+    next_id: u32,
+    /// Send time of the pings still waiting for an answer
+    in_flight: HashMap<u32, f64>,
+    /// Whether each of the last pings was answered
+    outcomes: VecDeque<bool>,
+    /// Last measured round trip time, `None` until the first pong
+    rtt_secs: Option<f32>,
+    jitter_secs: f32,
+    timer: Timer,
+}
+
+impl Default for PingTracker {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            in_flight: HashMap::default(),
+            outcomes: VecDeque::default(),
+            rtt_secs: None,
+            jitter_secs: 0.0,
+            timer: Timer::from_seconds(PING_INTERVAL_SECS, TimerMode::Repeating),
+        }
+    }
+}
+
+impl PingTracker {
+    fn record_outcome(&mut self, answered: bool) {
+        if self.outcomes.len() == LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(answered);
+    }
+
+    pub(crate) fn rtt_secs(&self) -> f32 {
+        self.rtt_secs.unwrap_or_default()
+    }
+
+    pub(crate) fn jitter_secs(&self) -> f32 {
+        self.jitter_secs
+    }
+
+    /// Fraction of the last pings that were not answered
+    pub(crate) fn packet_loss(&self) -> f32 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        lost as f32 / self.outcomes.len() as f32
+    }
+}
+
+pub(crate) fn send_pings(
+    time: Res<Time>,
+    mut tracker: ResMut<PingTracker>,
+    mut channel_bytes: ResMut<ChannelBytes>,
+    mut connection: ResMut<ConnectionManager>,
+) {
+    let now = time.elapsed_seconds_f64();
+    let timed_out: Vec<u32> = tracker
+        .in_flight
+        .iter()
+        .filter(|(_, sent)| now - **sent > PING_TIMEOUT_SECS)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in timed_out {
+        tracker.in_flight.remove(&id);
+        tracker.record_outcome(false);
+    }
+
+    if !tracker.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let id = tracker.next_id;
+    let message = PingMessage { id };
+    if connection.send_message::<PingChannel, _>(&message).is_ok() {
+        channel_bytes.record_sent::<PingChannel, _>(&message);
+        tracker.next_id = tracker.next_id.wrapping_add(1);
+        tracker.in_flight.insert(id, now);
+    }
+}
+
+pub(crate) fn receive_pongs(
+    time: Res<Time>,
+    mut tracker: ResMut<PingTracker>,
+    mut events: EventReader<MessageEvent<PingMessage>>,
+) {
+    let now = time.elapsed_seconds_f64();
+    for event in events.read() {
+        let Some(sent) = tracker.in_flight.remove(&event.message().id) else {
+            continue;
+        };
+        let rtt = (now - sent) as f32;
+        // Same smoothing as RFC 3550's interarrival jitter. The first sample only seeds the
+        // previous round trip time, there is nothing to compare it with yet
+        let previous_rtt = tracker.rtt_secs.unwrap_or(rtt);
+        tracker.jitter_secs += ((rtt - previous_rtt).abs() - tracker.jitter_secs) / 16.0;
+        tracker.rtt_secs = Some(rtt);
+        tracker.record_outcome(true);
+    }
+}
+
+/// Echo pings back to the client that sent them
+pub(crate) fn answer_pings(
+    mut events: EventReader<ServerMessageEvent<PingMessage>>,
+    mut connection: ResMut<ServerConnectionManager>,
+) {
+    for event in events.read() {
+        let _ = connection.send_message_to_target::<PingChannel, _>(
+            &mut event.message().clone(),
+            NetworkTarget::Single(*event.context()),
+        );
+    }
+}
+
+/// Count the messages received from the server on each channel
+pub(crate) fn count_received_messages(
+    mut channel_bytes: ResMut<ChannelBytes>,
+    mut pongs: EventReader<MessageEvent<PingMessage>>,
+    mut profiles: EventReader<MessageEvent<MovementProfileMessage>>,
+    mut levels: EventReader<MessageEvent<LevelMessage>>,
+    mut shots: EventReader<MessageEvent<ShotMessage>>,
+) {
+    for event in pongs.read() {
+        channel_bytes.record_received::<PingChannel, _>(event.message());
+    }
+    for event in profiles.read() {
+        channel_bytes.record_received::<ConfigChannel, _>(event.message());
+    }
+    for event in levels.read() {
+        channel_bytes.record_received::<ConfigChannel, _>(event.message());
+    }
+    for event in shots.read() {
+        channel_bytes.record_received::<CombatChannel, _>(event.message());
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sample_netgraph(
+    time: Res<Time>,
+    mut netgraph: ResMut<NetGraph>,
+    mut channel_bytes: ResMut<ChannelBytes>,
+    tracker: Res<PingTracker>,
+    diagnostics: Res<DiagnosticsStore>,
+    tick_manager: Res<TickManager>,
+    confirmed_query: Query<&Confirmed>,
+    input_query: Query<&InputBuffer<CharacterAction>, (With<Controlled>, With<Predicted>)>,
+) {
+    if !netgraph.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let smoothed = |path: &DiagnosticPath| {
+        diagnostics
+            .get(path)
+            .and_then(|diagnostic| diagnostic.smoothed())
+            .unwrap_or_default() as f32
+    };
+    // The most recent server tick we received state for
+    let tick = tick_manager.tick();
+    let tick_offset = confirmed_query
+        .iter()
+        .map(|confirmed| tick - confirmed.tick)
+        .min()
+        .unwrap_or_default();
+    // Inputs are buffered for the tick they will be applied on, so the newest one is as far
+    // ahead as the input delay in effect
+    let input_delay = input_query
+        .get_single()
+        .ok()
+        .and_then(|input_buffer| input_buffer.get_last_with_tick())
+        .map_or(0, |(last_tick, _)| (last_tick - tick).max(0));
+
+    let bytes_in = smoothed(&IoDiagnosticsPlugin::BYTES_IN);
+    let bytes_out = smoothed(&IoDiagnosticsPlugin::BYTES_OUT);
+    let interval_secs = netgraph.timer.duration().as_secs_f32();
+    netgraph.channels_in = channel_rates(
+        &mut channel_bytes.received,
+        interval_secs,
+        bytes_in,
+        "replication and inputs",
+    );
+    netgraph.channels_out =
+        channel_rates(&mut channel_bytes.sent, interval_secs, bytes_out, "inputs");
+
+    netgraph.push(NetGraphSeries::RoundTripTime, tracker.rtt_secs() * 1000.0);
+    netgraph.push(NetGraphSeries::Jitter, tracker.jitter_secs * 1000.0);
+    netgraph.push(NetGraphSeries::PacketLoss, tracker.packet_loss() * 100.0);
+    netgraph.push(NetGraphSeries::BytesIn, bytes_in);
+    netgraph.push(NetGraphSeries::BytesOut, bytes_out);
+    netgraph.push(NetGraphSeries::TickOffset, tick_offset as f32);
+    netgraph.push(NetGraphSeries::InputDelay, input_delay as f32);
+}
@@ -5,20 +5,39 @@ use avian3d::prelude::Rotation;
 use bevy::app::App;
 use bevy::app::Plugin;
 use bevy::core::Name;
+use bevy::prelude::default;
 use bevy::prelude::Color;
 use bevy::prelude::Component;
 use bevy::prelude::Reflect;
+use bevy::prelude::Vec3;
+use leafwing_abilities::prelude::{ChargeState, CooldownState};
+use leafwing_abilities::Abilitylike;
 use leafwing_input_manager::Actionlike;
 use lightyear::channel::builder::ChannelDirection;
 use lightyear::client::components::ComponentSyncMode;
+use lightyear::prelude::AppChannelExt;
 use lightyear::prelude::AppComponentExt;
+use lightyear::prelude::AppMessageExt;
+use lightyear::prelude::Channel;
+use lightyear::prelude::ChannelMode;
+use lightyear::prelude::ChannelSettings;
+use lightyear::prelude::ClientId;
 use lightyear::prelude::LeafwingInputPlugin;
+use lightyear::prelude::ReliableSettings;
 use lightyear::prelude::ReplicationGroup;
+use lightyear::prelude::Tick;
 use lightyear::utils::avian3d::position;
 use lightyear::utils::avian3d::rotation;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::level::LevelDefinition;
+use crate::prop::interact::HeldProp;
+use crate::prop::Prop;
+use crate::team::Team;
+
 pub const REPLICATION_GROUP: ReplicationGroup = ReplicationGroup::new_id(1);
 
 #[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -28,46 +47,230 @@ pub struct ColorComponent(pub(crate) Color);
 pub struct CharacterMarker;
 
 #[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
-pub struct FloorMarker;
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+}
+
+/// Present on a character while it is dead; it ignores inputs until it respawns
+#[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Dead {
+    pub respawn_tick: Tick,
+}
+
+/// The client controlling a character
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlayerId(pub ClientId);
 
 #[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
-pub struct BlockMarker;
+pub struct Projectile {
+    pub shooter: ClientId,
+    pub spawn_tick: Tick,
+}
+
+/// Reliable channel used to send configuration that must match between server and clients
+#[derive(Channel)]
+pub struct ConfigChannel;
+
+/// Movement profile the server simulates with, sent on connect and whenever it is reloaded
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct MovementProfileMessage {
+    pub hash: u64,
+    pub config: MovementConfig,
+}
+
+/// Level the server is running, sent on connect so the client can load its geometry
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LevelMessage {
+    pub name: String,
+    pub hash: u64,
+}
+
+impl LevelMessage {
+    pub fn new(level: &LevelDefinition) -> Self {
+        Self {
+            name: level.name.clone(),
+            hash: level.level_hash(),
+        }
+    }
+}
+
+/// Vote from a client to end the round and move on to the next level
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct MapVoteMessage;
+
+/// Channel for gameplay events such as shots
+#[derive(Channel)]
+pub struct CombatChannel;
+
+/// Unreliable channel for the netgraph's pings, so that lost packets show up as lost pings
+#[derive(Channel)]
+pub struct PingChannel;
+
+/// Sent by the client and echoed back by the server to measure round trip time and loss
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct PingMessage {
+    pub id: u32,
+}
+
+/// A hitscan shot resolved by the server
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ShotMessage {
+    pub shooter: ClientId,
+    pub origin: Vec3,
+    pub end: Vec3,
+    pub hit: bool,
+}
+
+/// How many ticks behind its predicted tick the client displays other characters
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ViewDelayMessage {
+    pub ticks: u16,
+}
+
+impl MovementProfileMessage {
+    pub fn new(config: &MovementConfig) -> Self {
+        Self {
+            hash: config.profile_hash(),
+            config: config.clone(),
+        }
+    }
+}
 
 #[derive(Copy, Deserialize, Serialize, Clone, Debug, PartialEq, Reflect, Hash, Eq)]
 pub enum CharacterAction {
     Move,
     Jump,
+    Sprint,
+    Crouch,
+    /// Horizontal aim direction in world space (x, z)
+    Aim,
+    /// Hold to pick up the aimed prop, release to throw it
+    Interact,
 }
 
 impl Actionlike for CharacterAction {
     fn input_control_kind(&self) -> leafwing_input_manager::InputControlKind {
         match self {
-            Self::Move => leafwing_input_manager::InputControlKind::DualAxis,
-            Self::Jump => leafwing_input_manager::InputControlKind::Button,
+            Self::Move | Self::Aim => leafwing_input_manager::InputControlKind::DualAxis,
+            Self::Jump | Self::Sprint | Self::Crouch | Self::Interact => {
+                leafwing_input_manager::InputControlKind::Button
+            }
         }
     }
 }
 
+#[derive(
+    Actionlike,
+    Abilitylike,
+    Copy,
+    Deserialize,
+    Serialize,
+    Clone,
+    Debug,
+    PartialEq,
+    Reflect,
+    Hash,
+    Eq,
+)]
+pub enum Ability {
+    Dash,
+    Fire,
+    Projectile,
+}
+
+impl Ability {
+    pub const ALL: [Ability; 3] = [Ability::Dash, Ability::Fire, Ability::Projectile];
+}
+
 pub(crate) struct ProtocolPlugin;
 
 impl Plugin for ProtocolPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(LeafwingInputPlugin::<CharacterAction>::default());
+        app.add_plugins(LeafwingInputPlugin::<Ability>::default());
+
+        app.add_channel::<ConfigChannel>(ChannelSettings {
+            mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+            ..default()
+        });
+
+        app.register_message::<MovementProfileMessage>(ChannelDirection::ServerToClient);
+        app.register_message::<LevelMessage>(ChannelDirection::ServerToClient);
+        app.register_message::<MapVoteMessage>(ChannelDirection::ClientToServer);
+
+        app.add_channel::<CombatChannel>(ChannelSettings {
+            mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+            ..default()
+        });
+
+        app.register_message::<ShotMessage>(ChannelDirection::ServerToClient);
+
+        app.add_channel::<PingChannel>(ChannelSettings {
+            mode: ChannelMode::UnorderedUnreliable,
+            ..default()
+        });
 
+        app.register_message::<PingMessage>(ChannelDirection::Bidirectional);
+        app.register_message::<ViewDelayMessage>(ChannelDirection::ClientToServer);
+
+        // Everything that can be predicted can also be interpolated, see ReplicationModes
         app.register_component::<ColorComponent>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once);
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
 
         app.register_component::<Name>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once);
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
 
         app.register_component::<CharacterMarker>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once);
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
+
+        app.register_component::<PlayerId>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
+
+        app.register_component::<Team>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Simple)
+            .add_interpolation(ComponentSyncMode::Simple);
+
+        // Health and death are decided by the server only, so they are never rolled back
+        app.register_component::<Health>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Simple)
+            .add_interpolation(ComponentSyncMode::Simple);
 
-        app.register_component::<FloorMarker>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once);
+        app.register_component::<Dead>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Simple)
+            .add_interpolation(ComponentSyncMode::Simple);
 
-        app.register_component::<BlockMarker>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once);
+        app.register_component::<Prop>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
+
+        app.register_component::<Projectile>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
+
+        app.register_component::<CharacterController>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full)
+            .add_interpolation(ComponentSyncMode::Simple);
+
+        app.register_component::<HeldProp>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full)
+            .add_map_entities();
+
+        app.register_component::<CooldownState<Ability>>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full);
+
+        app.register_component::<ChargeState<Ability>>(ChannelDirection::ServerToClient)
+            .add_prediction(ComponentSyncMode::Full);
 
         app.register_component::<LinearVelocity>(ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Full);
@@ -77,11 +280,13 @@ impl Plugin for ProtocolPlugin {
 
         app.register_component::<Position>(ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Full)
+            .add_interpolation(ComponentSyncMode::Full)
             .add_interpolation_fn(position::lerp)
             .add_correction_fn(position::lerp);
 
         app.register_component::<Rotation>(ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Full)
+            .add_interpolation(ComponentSyncMode::Full)
             .add_interpolation_fn(rotation::lerp)
             .add_correction_fn(rotation::lerp);
     }
@@ -0,0 +1,336 @@
+//! Recording of the inputs the server applies, so a session can be replayed locally.
+//!
+//! The file starts with a [`RecordingHeader`] line, followed by one [`RecordedEvent`] per
+//! line, both in RON. Ticks are counted from the start of the recording so they don't wrap.
+//!
+//! Inputs are recorded as the ActionStates the server applied on each tick rather than as the
+//! received `InputMessage`s, because the messages alone don't say what was simulated. A late
+//! input arrives after its tick was simulated with the previous input, and a lost one is never
+//! applied: the server kept the previous ActionState on those ticks, and replaying the
+//! messages would apply inputs the session never saw. Resent inputs would be applied twice.
+//! The ActionState at the start of a tick is what the character, ability and interaction
+//! systems read, after all of that.
+//!
+//! Only changes are written: on the ticks in between the server applied the same ActionState
+//! again, which is what the replay does with the last one it read.
+use avian3d::prelude::Position;
+use bevy::asset::ron;
+use bevy::log::{info, warn};
+use bevy::math::{Vec2, Vec3};
+use bevy::prelude::{
+    Added, DetectChanges, EventReader, Query, RemovedComponents, Res, ResMut, Resource, With,
+};
+use bevy::utils::HashMap;
+use leafwing_input_manager::buttonlike::ButtonState;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::ClientId;
+use lightyear::server::events::{ConnectEvent, DisconnectEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::app::line_log::LineLog;
+use crate::character::config::MovementConfig;
+use crate::level::CurrentLevel;
+use crate::netcode::protocol::{Ability, CharacterAction, CharacterMarker, Dead, PlayerId};
+
+/// Characters' positions are written every this many ticks, to check replays against
+pub(crate) const CHECKPOINT_TICKS: u32 = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RecordingHeader {
+    /// Level the server was running when the recording started
+    pub(crate) level: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum RecordedEvent {
+    Connected {
+        tick: u32,
+        client_id: ClientId,
+    },
+    Disconnected {
+        tick: u32,
+        client_id: ClientId,
+    },
+    /// The client's character was created at this position
+    Spawned {
+        tick: u32,
+        client_id: ClientId,
+        position: Vec3,
+    },
+    /// The inputs applied to the client's character from this tick on
+    Input {
+        tick: u32,
+        client_id: ClientId,
+        input: RecordedInput,
+    },
+    /// The character died at the end of the tick's inputs, before physics
+    Died {
+        tick: u32,
+        client_id: ClientId,
+    },
+    /// The character respawned at the end of the tick's inputs, before physics
+    Respawned {
+        tick: u32,
+        client_id: ClientId,
+        position: Vec3,
+    },
+    MovementProfile {
+        tick: u32,
+        config: MovementConfig,
+    },
+    LevelChanged {
+        tick: u32,
+        name: String,
+    },
+    /// Where the server had the character after the tick's physics
+    Checkpoint {
+        tick: u32,
+        client_id: ClientId,
+        position: Vec3,
+    },
+}
+
+impl RecordedEvent {
+    pub(crate) fn tick(&self) -> u32 {
+        match self {
+            RecordedEvent::Connected { tick, .. }
+            | RecordedEvent::Disconnected { tick, .. }
+            | RecordedEvent::Spawned { tick, .. }
+            | RecordedEvent::Input { tick, .. }
+            | RecordedEvent::Died { tick, .. }
+            | RecordedEvent::Respawned { tick, .. }
+            | RecordedEvent::MovementProfile { tick, .. }
+            | RecordedEvent::LevelChanged { tick, .. }
+            | RecordedEvent::Checkpoint { tick, .. } => *tick,
+        }
+    }
+}
+
+/// The part of a character's ActionStates that the simulation reads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RecordedInput {
+    movement: Vec2,
+    aim: Vec2,
+    jump: ButtonState,
+    sprint: ButtonState,
+    crouch: ButtonState,
+    interact: ButtonState,
+    /// The abilities, in the order of [`Ability::ALL`]
+    #[serde(default)]
+    abilities: [ButtonState; Ability::ALL.len()],
+}
+
+impl RecordedInput {
+    const BUTTONS: [CharacterAction; 4] = [
+        CharacterAction::Jump,
+        CharacterAction::Sprint,
+        CharacterAction::Crouch,
+        CharacterAction::Interact,
+    ];
+
+    fn from_action_states(
+        action_state: &ActionState<CharacterAction>,
+        abilities: &ActionState<Ability>,
+    ) -> Self {
+        let button = |action| {
+            action_state
+                .button_data(&action)
+                .map(|data| data.state)
+                .unwrap_or_default()
+        };
+        Self {
+            movement: action_state.axis_pair(&CharacterAction::Move),
+            aim: action_state.axis_pair(&CharacterAction::Aim),
+            jump: button(CharacterAction::Jump),
+            sprint: button(CharacterAction::Sprint),
+            crouch: button(CharacterAction::Crouch),
+            interact: button(CharacterAction::Interact),
+            abilities: Ability::ALL.map(|ability| {
+                abilities
+                    .button_data(&ability)
+                    .map(|data| data.state)
+                    .unwrap_or_default()
+            }),
+        }
+    }
+
+    /// Set the ActionStates to exactly the recorded ones, including just pressed buttons
+    pub(crate) fn apply(
+        &self,
+        action_state: &mut ActionState<CharacterAction>,
+        abilities: &mut ActionState<Ability>,
+    ) {
+        action_state.set_axis_pair(&CharacterAction::Move, self.movement);
+        action_state.set_axis_pair(&CharacterAction::Aim, self.aim);
+        let states = [self.jump, self.sprint, self.crouch, self.interact];
+        for (action, state) in Self::BUTTONS.into_iter().zip(states) {
+            action_state.button_data_mut_or_default(&action).state = state;
+        }
+        for (ability, state) in Ability::ALL.into_iter().zip(self.abilities) {
+            abilities.button_data_mut_or_default(&ability).state = state;
+        }
+    }
+}
+
+/// File the server records the session to, if enabled in the settings
+#[derive(Resource, Default)]
+pub(crate) struct InputRecorder {
+    log: LineLog,
+    /// The next tick to be simulated, counted from the start of the recording
+    tick: u32,
+    last_inputs: HashMap<ClientId, RecordedInput>,
+}
+
+impl InputRecorder {
+    pub(crate) fn create(path: &str, header: &RecordingHeader) -> Self {
+        let header = match ron::ser::to_string(header) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("Could not serialize the input recording header: {e}");
+                return Self::default();
+            }
+        };
+        let log = LineLog::create("input recording", path, header);
+        if log.is_open() {
+            info!("Recording inputs to {path}");
+        }
+        Self {
+            log,
+            ..Self::default()
+        }
+    }
+
+    fn write(&mut self, event: &RecordedEvent) {
+        if !self.log.is_open() {
+            return;
+        }
+        match ron::ser::to_string(event) {
+            Ok(line) => self.log.write_line(line),
+            Err(e) => warn!("Could not serialize a recorded event: {e}"),
+        }
+    }
+}
+
+impl AsMut<LineLog> for InputRecorder {
+    fn as_mut(&mut self) -> &mut LineLog {
+        &mut self.log
+    }
+}
+
+pub(crate) fn is_recording(recorder: Res<InputRecorder>) -> bool {
+    recorder.log.is_open()
+}
+
+/// Connections, new characters, movement profile and level changes, between two ticks
+pub(crate) fn record_session_events(
+    mut recorder: ResMut<InputRecorder>,
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    movement_config: Res<MovementConfig>,
+    current_level: Res<CurrentLevel>,
+    spawned_query: Query<(&PlayerId, &Position), Added<PlayerId>>,
+) {
+    let tick = recorder.tick;
+    if movement_config.is_changed() {
+        recorder.write(&RecordedEvent::MovementProfile {
+            tick,
+            config: movement_config.clone(),
+        });
+    }
+    if current_level.is_changed() && !current_level.is_added() {
+        recorder.write(&RecordedEvent::LevelChanged {
+            tick,
+            name: current_level.0.name.clone(),
+        });
+    }
+    for event in connections.read() {
+        recorder.write(&RecordedEvent::Connected {
+            tick,
+            client_id: event.client_id,
+        });
+    }
+    for (player_id, position) in &spawned_query {
+        recorder.write(&RecordedEvent::Spawned {
+            tick,
+            client_id: player_id.0,
+            position: position.0,
+        });
+    }
+    for event in disconnections.read() {
+        recorder.last_inputs.remove(&event.client_id);
+        recorder.write(&RecordedEvent::Disconnected {
+            tick,
+            client_id: event.client_id,
+        });
+    }
+}
+
+/// The inputs about to be applied this tick, for the characters whose inputs changed
+pub(crate) fn record_inputs(
+    mut recorder: ResMut<InputRecorder>,
+    query: Query<
+        (
+            &PlayerId,
+            &ActionState<CharacterAction>,
+            &ActionState<Ability>,
+        ),
+        With<CharacterMarker>,
+    >,
+) {
+    let tick = recorder.tick;
+    for (player_id, action_state, abilities) in &query {
+        let input = RecordedInput::from_action_states(action_state, abilities);
+        if recorder.last_inputs.get(&player_id.0) == Some(&input) {
+            continue;
+        }
+        recorder.last_inputs.insert(player_id.0, input);
+        recorder.write(&RecordedEvent::Input {
+            tick,
+            client_id: player_id.0,
+            input,
+        });
+    }
+}
+
+/// Deaths and respawns of this tick, which move characters outside of their inputs
+pub(crate) fn record_deaths(
+    mut recorder: ResMut<InputRecorder>,
+    mut respawned: RemovedComponents<Dead>,
+    died_query: Query<&PlayerId, Added<Dead>>,
+    character_query: Query<(&PlayerId, &Position), With<CharacterMarker>>,
+) {
+    let tick = recorder.tick;
+    for player_id in &died_query {
+        recorder.write(&RecordedEvent::Died {
+            tick,
+            client_id: player_id.0,
+        });
+    }
+    for entity in respawned.read() {
+        if let Ok((player_id, position)) = character_query.get(entity) {
+            recorder.write(&RecordedEvent::Respawned {
+                tick,
+                client_id: player_id.0,
+                position: position.0,
+            });
+        }
+    }
+}
+
+/// Periodic positions of the characters after physics, then move on to the next tick
+pub(crate) fn record_checkpoints(
+    mut recorder: ResMut<InputRecorder>,
+    query: Query<(&PlayerId, &Position), With<CharacterMarker>>,
+) {
+    let tick = recorder.tick;
+    if tick % CHECKPOINT_TICKS == 0 {
+        for (player_id, position) in &query {
+            recorder.write(&RecordedEvent::Checkpoint {
+                tick,
+                client_id: player_id.0,
+                position: position.0,
+            });
+        }
+    }
+    recorder.tick += 1;
+}
@@ -0,0 +1,69 @@
+//! How a client predicts the characters of other players.
+//!
+//! Remote inputs reach us through the server's rebroadcast, so they are always late: when a
+//! predicted remote character is simulated for a tick we have no input for, we have to guess.
+//! Every wrong guess shows up as a misprediction and a rollback once the real input arrives.
+//! [`RemoteInputPolicy::Interpolate`] doesn't guess at all: remote characters are interpolated.
+use bevy::prelude::Resource;
+use bevy::utils::Instant;
+use leafwing_input_manager::prelude::ActionState;
+use serde::{Deserialize, Serialize};
+
+use crate::netcode::protocol::CharacterAction;
+use crate::netcode::replication::ReplicationMode;
+
+/// Ticks over which [`RemoteInputPolicy::DecayToZero`] fades the movement input out
+const DECAY_TICKS: i16 = 8;
+
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RemoteInputPolicy {
+    /// Keep applying the last received input, as held buttons. Good for players who keep
+    /// running in a straight line, bad for players who stop or turn.
+    #[default]
+    RepeatLast,
+    /// Like `RepeatLast`, but the movement input fades to zero over a few ticks and buttons are
+    /// released once it has. Overshoots less when a player stops.
+    DecayToZero,
+    /// Don't predict remote characters, interpolate them. No remote misprediction, but they
+    /// are shown in the past. Replicates characters with
+    /// [`ReplicationMode::PredictOwnerInterpolateOthers`] whatever the replication modes say.
+    Interpolate,
+}
+
+impl RemoteInputPolicy {
+    /// The replication mode of characters, once this policy is taken into account
+    pub(crate) fn character_replication(&self, configured: ReplicationMode) -> ReplicationMode {
+        match self {
+            RemoteInputPolicy::Interpolate => ReplicationMode::PredictOwnerInterpolateOthers,
+            RemoteInputPolicy::RepeatLast | RemoteInputPolicy::DecayToZero => configured,
+        }
+    }
+
+    /// Guess the input of a remote character, `age` ticks after the last input we received.
+    ///
+    /// Presses are never extrapolated: the guess only holds buttons, so a jump or ability
+    /// press isn't repeated on every tick we are missing. With `Interpolate` no remote
+    /// character is predicted, the last input is repeated should one still be.
+    pub(crate) fn extrapolate(
+        &self,
+        last: &ActionState<CharacterAction>,
+        age: i16,
+    ) -> ActionState<CharacterAction> {
+        let mut action_state = last.clone();
+        let now = Instant::now();
+        action_state.tick(now, now);
+        action_state.release(&CharacterAction::Jump);
+
+        if *self == RemoteInputPolicy::DecayToZero {
+            let remaining = 1.0 - (age as f32 / DECAY_TICKS as f32).clamp(0.0, 1.0);
+            let movement = last.axis_pair(&CharacterAction::Move);
+            action_state.set_axis_pair(&CharacterAction::Move, movement * remaining);
+            if remaining == 0.0 {
+                action_state.release(&CharacterAction::Sprint);
+                action_state.release(&CharacterAction::Crouch);
+                action_state.release(&CharacterAction::Interact);
+            }
+        }
+        action_state
+    }
+}
@@ -0,0 +1,57 @@
+//! Whether clients predict or interpolate each kind of replicated entity.
+use bevy::prelude::Resource;
+use lightyear::prelude::server::SyncTarget;
+use lightyear::prelude::{ClientId, NetworkTarget};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ReplicationMode {
+    /// Every client predicts the entity. Interactions with it are instant, at the cost of
+    /// mispredictions when other players act on it.
+    ///
+    /// Characters are the exception: only their teammates predict them and enemies
+    /// interpolate them, because enemies don't get the ability state predicting needs. See
+    /// [`update_team_replication`](crate::team::update_team_replication).
+    #[default]
+    PredictAll,
+    /// The owning client predicts the entity and everyone else interpolates it. Entities
+    /// without an owner are interpolated by everyone.
+    PredictOwnerInterpolateOthers,
+    /// Every client interpolates the entity, including its owner.
+    InterpolateAll,
+}
+
+impl ReplicationMode {
+    /// The sync target to replicate an entity owned by `owner` with
+    pub fn sync_target(&self, owner: Option<ClientId>) -> SyncTarget {
+        match (self, owner) {
+            (ReplicationMode::PredictAll, _) => SyncTarget {
+                prediction: NetworkTarget::All,
+                interpolation: NetworkTarget::None,
+            },
+            (ReplicationMode::PredictOwnerInterpolateOthers, Some(owner)) => SyncTarget {
+                prediction: NetworkTarget::Single(owner),
+                interpolation: NetworkTarget::AllExceptSingle(owner),
+            },
+            (ReplicationMode::PredictOwnerInterpolateOthers, None)
+            | (ReplicationMode::InterpolateAll, _) => SyncTarget {
+                prediction: NetworkTarget::None,
+                interpolation: NetworkTarget::All,
+            },
+        }
+    }
+
+    /// Whether the owner of an entity predicts it
+    pub fn owner_predicts(&self) -> bool {
+        *self != ReplicationMode::InterpolateAll
+    }
+}
+
+/// Replication mode of each kind of entity. Both the server and the clients read it, so it
+/// comes from the shared settings.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ReplicationModes {
+    pub characters: ReplicationMode,
+    pub props: ReplicationMode,
+    pub projectiles: ReplicationMode,
+}
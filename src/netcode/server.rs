@@ -1,81 +1,440 @@
+use std::fs;
+use std::time::SystemTime;
+
 use avian3d::prelude::Position;
 use avian3d::prelude::SpatialQuery;
 use bevy::app::FixedUpdate;
 use bevy::app::Plugin;
 use bevy::app::PreUpdate;
 use bevy::app::Update;
-use bevy::color::palettes::css;
 use bevy::core::Name;
 use bevy::log::info;
 use bevy::prelude::*;
 use leafwing_input_manager::prelude::ActionState;
 use lightyear::channel::builder::InputChannel;
 use lightyear::prelude::server::ControlledBy;
+use lightyear::prelude::server::NetworkRelevanceMode;
 use lightyear::prelude::server::Replicate;
 use lightyear::prelude::server::ServerCommands;
-use lightyear::prelude::server::SyncTarget;
 use lightyear::prelude::InputMessage;
+use lightyear::prelude::LeafwingUserAction;
 use lightyear::prelude::MainSet;
+use lightyear::prelude::TickManager;
 use lightyear::server::connection::ConnectionManager;
 use lightyear::server::events::ConnectEvent;
 use lightyear::server::events::MessageEvent;
 use lightyear::shared::replication::network_target::NetworkTarget;
 
+use crate::abilities::{apply_ability_action, AbilitiesBundle, AbilityQuery};
+use crate::app::line_log::flush_on_exit;
+use crate::character::config::{parse_movement_profile, MovementConfig};
+use crate::character::CharacterController;
+use crate::combat::health::{apply_damage, respawn_characters, MAX_HEALTH};
+use crate::combat::hitscan::{
+    receive_view_delay, record_position_history, resolve_hitscan_shots, shot_ray, HitscanShot,
+    PositionHistory, ShooterViewDelay,
+};
+use crate::combat::projectile::{handle_projectiles, ProjectileBundle};
+use crate::combat::DamageEvent;
+use crate::level::rotation::{receive_map_votes, ChangeLevel, MapRotation, MapVotes};
+use crate::level::spawn::SpawnPointSelector;
+use crate::level::spawn::SpawnPolicy;
+use crate::level::{
+    despawn_level, load_level, spawn_level_geometry, CurrentLevel, LevelDefinition, LevelEntity,
+    DEFAULT_LEVEL,
+};
+use crate::netcode::interest::{
+    log_relevance_stats, relevance_update_due, update_relevance, InterestSettings,
+    RelevanceStatsTimer, RelevantEntities,
+};
+use crate::netcode::netgraph::answer_pings;
 use crate::netcode::protocol::*;
+use crate::netcode::recording::{
+    is_recording, record_checkpoints, record_deaths, record_inputs, record_session_events,
+    InputRecorder, RecordingHeader,
+};
+use crate::netcode::replication::ReplicationModes;
 use crate::netcode::shared::*;
+use crate::prop::interact::{handle_interactions, HeldProp};
+use crate::prop::PropPhysicsBundle;
+use crate::team::{balanced_team, update_team_replication, Team};
+
+pub struct ZinnobreIronServerPlugin {
+    pub interest: InterestSettings,
+    /// Names of the levels to cycle through, the first one is loaded on startup
+    pub map_rotation: Vec<String>,
+    /// File to record the session's inputs to, for replays
+    pub input_recording: Option<String>,
+}
 
-pub struct ZinnobreIronServerPlugin;
+/// Movement profile loaded by the server and sent to the clients
+const MOVEMENT_PROFILE_PATH: &str = "assets/movement_profile.ron";
 
 impl Plugin for ZinnobreIronServerPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
+        app.insert_resource(load_movement_profile());
+        let map_rotation = MapRotation::new(self.map_rotation.clone());
+        let level = load_level(map_rotation.current().unwrap_or(DEFAULT_LEVEL))
+            .expect("Could not load the first level of the rotation");
+        app.insert_resource(level.spawn_policy);
+        app.insert_resource(
+            self.input_recording
+                .as_deref()
+                .map(|path| {
+                    InputRecorder::create(
+                        path,
+                        &RecordingHeader {
+                            level: level.name.clone(),
+                        },
+                    )
+                })
+                .unwrap_or_default(),
+        );
+        app.insert_resource(CurrentLevel(level));
+        app.insert_resource(map_rotation);
+        app.init_resource::<MapVotes>();
+        app.add_event::<ChangeLevel>();
+        app.insert_resource(self.interest);
+        app.init_resource::<RelevantEntities>();
+        app.init_resource::<RelevanceStatsTimer>();
+        app.insert_resource(MovementProfileWatcher {
+            last_modified: movement_profile_modified(),
+            timer: Timer::from_seconds(1.0, TimerMode::Repeating),
+        });
+
         app.add_systems(Startup, init);
-        app.add_systems(PreUpdate, replicate_inputs.after(MainSet::EmitEvents));
-        app.add_systems(FixedUpdate, handle_character_actions.in_set(FixedSet::Main));
-        app.add_systems(Update, handle_connections);
+        app.add_systems(Last, flush_on_exit::<InputRecorder>);
+        app.add_systems(
+            PreUpdate,
+            (
+                replicate_inputs::<CharacterAction>,
+                replicate_inputs::<Ability>,
+            )
+                .after(MainSet::EmitEvents),
+        );
+        app.add_event::<HitscanShot>();
+        app.add_event::<DamageEvent>();
+        app.add_systems(
+            FixedUpdate,
+            (
+                record_position_history,
+                handle_character_actions,
+                handle_ability_actions,
+                handle_interactions::<()>,
+                resolve_hitscan_shots,
+                handle_projectiles,
+                apply_damage,
+                respawn_characters,
+            )
+                .chain()
+                .in_set(FixedSet::Main),
+        );
+        app.add_systems(
+            FixedUpdate,
+            (
+                record_inputs
+                    .in_set(FixedSet::Main)
+                    .before(handle_character_actions),
+                record_deaths
+                    .after(FixedSet::Main)
+                    .before(FixedSet::Physics),
+                record_checkpoints.after(FixedSet::Physics),
+            )
+                .run_if(is_recording),
+        );
+        app.add_systems(
+            FixedUpdate,
+            update_relevance
+                .after(FixedSet::Physics)
+                .run_if(relevance_update_due),
+        );
+        app.add_systems(
+            Update,
+            (
+                handle_connections,
+                update_team_replication.after(handle_connections),
+                log_relevance_stats,
+                receive_view_delay,
+                send_movement_profile,
+                send_level,
+                (receive_map_votes, change_level).chain(),
+                hot_reload_movement_profile,
+                answer_pings,
+                record_session_events
+                    .after(handle_connections)
+                    .after(change_level)
+                    .run_if(is_recording),
+            ),
+        );
+    }
+}
+
+/// Polls the movement profile on disk so it can be tuned while the server is running
+#[derive(Resource)]
+struct MovementProfileWatcher {
+    last_modified: Option<SystemTime>,
+    timer: Timer,
+}
+
+fn movement_profile_modified() -> Option<SystemTime> {
+    fs::metadata(MOVEMENT_PROFILE_PATH)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn load_movement_profile() -> MovementConfig {
+    let config = match fs::read_to_string(MOVEMENT_PROFILE_PATH) {
+        Ok(profile_str) => parse_movement_profile(&profile_str),
+        Err(e) => {
+            warn!("Could not read {MOVEMENT_PROFILE_PATH} ({e}), using the embedded profile");
+            parse_movement_profile(include_str!("../../assets/movement_profile.ron"))
+        }
+    }
+    .expect("Could not deserialize the movement profile");
+    info!(hash = config.profile_hash(), "Loaded movement profile");
+    config
+}
+
+fn hot_reload_movement_profile(
+    time: Res<Time>,
+    mut watcher: ResMut<MovementProfileWatcher>,
+    mut movement_config: ResMut<MovementConfig>,
+    mut connection: ResMut<ConnectionManager>,
+) {
+    if !watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let modified = movement_profile_modified();
+    if modified == watcher.last_modified {
+        return;
+    }
+    watcher.last_modified = modified;
+
+    let Ok(profile_str) = fs::read_to_string(MOVEMENT_PROFILE_PATH) else {
+        return;
+    };
+    match parse_movement_profile(&profile_str) {
+        Ok(config) if config != *movement_config => {
+            info!(hash = config.profile_hash(), "Reloaded movement profile");
+            *movement_config = config;
+            connection
+                .send_message_to_target::<ConfigChannel, _>(
+                    &mut MovementProfileMessage::new(&movement_config),
+                    NetworkTarget::All,
+                )
+                .unwrap();
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Could not parse the movement profile, keeping the current one: {e}"),
+    }
+}
+
+/// Send the movement profile to newly connected clients so they predict with the same values
+fn send_movement_profile(
+    mut connections: EventReader<ConnectEvent>,
+    movement_config: Res<MovementConfig>,
+    mut connection: ResMut<ConnectionManager>,
+) {
+    for event in connections.read() {
+        connection
+            .send_message_to_target::<ConfigChannel, _>(
+                &mut MovementProfileMessage::new(&movement_config),
+                NetworkTarget::Single(event.client_id),
+            )
+            .unwrap();
     }
 }
 
-fn handle_character_actions(
+pub(crate) fn handle_character_actions(
     time: Res<Time>,
+    movement_config: Res<MovementConfig>,
     spatial_query: SpatialQuery,
-    mut query: Query<(&ActionState<CharacterAction>, CharacterQuery)>,
+    mut query: Query<(&ActionState<CharacterAction>, CharacterQuery), Without<Dead>>,
 ) {
     for (action_state, mut character) in &mut query {
-        apply_character_action(&time, &spatial_query, action_state, &mut character);
+        apply_character_action(
+            &time,
+            &movement_config,
+            &spatial_query,
+            action_state,
+            &mut character,
+        );
     }
 }
 
-fn init(mut commands: Commands) {
+pub(crate) fn handle_ability_actions(
+    mut commands: Commands,
+    time: Res<Time>,
+    movement_config: Res<MovementConfig>,
+    replication_modes: Res<ReplicationModes>,
+    tick_manager: Res<TickManager>,
+    mut shots: EventWriter<HitscanShot>,
+    mut query: Query<
+        (
+            &ActionState<Ability>,
+            &ActionState<CharacterAction>,
+            &PlayerId,
+            AbilityQuery,
+            CharacterQuery,
+        ),
+        Without<Dead>,
+    >,
+) {
+    for (action_state, character_action_state, player_id, mut abilities, mut character) in
+        &mut query
+    {
+        let activated = apply_ability_action(
+            &time,
+            &movement_config,
+            action_state,
+            character_action_state,
+            &mut abilities,
+            &mut character,
+        );
+        let (origin, direction) = shot_ray(
+            &movement_config,
+            &character.controller,
+            character.position.0,
+            character_action_state,
+        );
+        if activated.contains(&Ability::Fire) {
+            shots.send(HitscanShot {
+                shooter: character.entity,
+                tick: tick_manager.tick(),
+                origin,
+                direction,
+            });
+        }
+        if activated.contains(&Ability::Projectile) {
+            commands.spawn((
+                ProjectileBundle::new(
+                    player_id.0,
+                    tick_manager.tick(),
+                    origin,
+                    direction,
+                    movement_config.capsule_radius,
+                ),
+                Replicate {
+                    sync: replication_modes.projectiles.sync_target(Some(player_id.0)),
+                    relevance_mode: NetworkRelevanceMode::InterestManagement,
+                    ..default()
+                },
+            ));
+        }
+    }
+}
+
+/// Tell newly connected clients which level to load
+fn send_level(
+    mut connections: EventReader<ConnectEvent>,
+    current_level: Res<CurrentLevel>,
+    mut connection: ResMut<ConnectionManager>,
+) {
+    for event in connections.read() {
+        connection
+            .send_message_to_target::<ConfigChannel, _>(
+                &mut LevelMessage::new(&current_level.0),
+                NetworkTarget::Single(event.client_id),
+            )
+            .unwrap();
+    }
+}
+
+fn init(
+    mut commands: Commands,
+    current_level: Res<CurrentLevel>,
+    replication_modes: Res<ReplicationModes>,
+) {
     commands.start_server();
+    spawn_level(&mut commands, &current_level.0, &replication_modes);
+}
 
-    commands.spawn((
-        Name::new("Floor"),
-        FloorPhysicsBundle::default(),
-        FloorMarker,
-        Position::new(Vec3::ZERO),
-        Replicate::default(),
-    ));
-
-    let block_replicate_component = Replicate {
-        sync: SyncTarget {
-            prediction: lightyear::prelude::NetworkTarget::All,
-            ..default()
-        },
+/// Spawn everything in the level: the local geometry, the replicated props and the spawn points
+fn spawn_level(commands: &mut Commands, level: &LevelDefinition, modes: &ReplicationModes) {
+    spawn_level_geometry(commands, level);
+
+    let prop_replicate_component = Replicate {
+        sync: modes.props.sync_target(None),
         group: REPLICATION_GROUP,
+        relevance_mode: NetworkRelevanceMode::InterestManagement,
         ..default()
     };
-    commands.spawn((
-        Name::new("Block"),
-        BlockPhysicsBundle::default(),
-        BlockMarker,
-        Position::new(Vec3::new(-1.0, 1.0, 0.0)),
-        block_replicate_component.clone(),
-    ));
+    for prop in &level.props {
+        commands.spawn((
+            Name::new("Prop"),
+            PropPhysicsBundle::new(&prop.prop),
+            prop.prop.clone(),
+            LevelEntity,
+            Position::new(prop.position),
+            prop_replicate_component.clone(),
+        ));
+    }
+
+    for spawn_point in &level.spawn_points {
+        commands.spawn((Name::new("SpawnPoint"), LevelEntity, spawn_point.clone()));
+    }
 }
 
-pub(crate) fn replicate_inputs(
+/// End the round and move everyone to another level, keeping the connections alive
+#[allow(clippy::too_many_arguments)]
+fn change_level(
+    mut commands: Commands,
+    mut change_events: EventReader<ChangeLevel>,
+    tick_manager: Res<TickManager>,
+    replication_modes: Res<ReplicationModes>,
+    mut map_rotation: ResMut<MapRotation>,
+    mut current_level: ResMut<CurrentLevel>,
+    mut spawn_policy: ResMut<SpawnPolicy>,
     mut connection: ResMut<ConnectionManager>,
-    mut input_events: ResMut<Events<MessageEvent<InputMessage<CharacterAction>>>>,
+    level_query: Query<Entity, With<LevelEntity>>,
+    projectile_query: Query<Entity, With<Projectile>>,
+    character_query: Query<Entity, With<CharacterMarker>>,
+) {
+    // Several requests in the same frame only change the level once
+    if change_events.is_empty() {
+        return;
+    }
+    change_events.clear();
+    let name = match map_rotation.advance() {
+        Some(name) => name.to_string(),
+        None => current_level.0.name.clone(),
+    };
+    let Some(level) = load_level(&name) else {
+        warn!(
+            name,
+            "Could not load the next level, staying on the current one"
+        );
+        return;
+    };
+    info!(name, "Changing level");
+
+    despawn_level(&mut commands, &level_query);
+    for entity in &projectile_query {
+        commands.entity(entity).despawn();
+    }
+    spawn_level(&mut commands, &level, &replication_modes);
+
+    // Characters respawn in the new level on the next tick, once its spawn points exist.
+    // They all respawn on that tick, and respawn_characters gives each a different point.
+    for entity in &character_query {
+        commands.entity(entity).insert(Dead {
+            respawn_tick: tick_manager.tick() + 1,
+        });
+    }
+
+    connection
+        .send_message_to_target::<ConfigChannel, _>(
+            &mut LevelMessage::new(&level),
+            NetworkTarget::All,
+        )
+        .unwrap();
+    *spawn_policy = level.spawn_policy;
+    current_level.0 = level;
+}
+
+pub(crate) fn replicate_inputs<A: LeafwingUserAction>(
+    mut connection: ResMut<ConnectionManager>,
+    mut input_events: ResMut<Events<MessageEvent<InputMessage<A>>>>,
 ) {
     for mut event in input_events.drain() {
         let client_id = *event.context();
@@ -91,57 +450,52 @@ pub(crate) fn replicate_inputs(
 pub(crate) fn handle_connections(
     mut connections: EventReader<ConnectEvent>,
     mut commands: Commands,
-    character_query: Query<Entity, With<CharacterMarker>>,
+    movement_config: Res<MovementConfig>,
+    spawn_points: SpawnPointSelector,
+    replication_modes: Res<ReplicationModes>,
+    team_query: Query<&Team, With<CharacterMarker>>,
 ) {
-    let mut num_characters = character_query.iter().count();
+    let mut teams: Vec<Team> = team_query.iter().copied().collect();
+    let mut spawn_positions = Vec::new();
     for connection in connections.read() {
         let client_id = connection.client_id;
         info!("Client connected with client-id {client_id:?}. Spawning character entity.");
         let replicate = Replicate {
-            sync: SyncTarget {
-                prediction: lightyear::prelude::NetworkTarget::All,
-                ..default()
-            },
+            sync: replication_modes.characters.sync_target(Some(client_id)),
             controlled_by: ControlledBy {
                 target: lightyear::prelude::NetworkTarget::Single(client_id),
                 ..default()
             },
             group: REPLICATION_GROUP,
+            relevance_mode: NetworkRelevanceMode::InterestManagement,
             ..default()
         };
 
-        let available_colors = [
-            css::LIMEGREEN,
-            css::PINK,
-            css::YELLOW,
-            css::AQUA,
-            css::CRIMSON,
-            css::GOLD,
-            css::ORANGE_RED,
-            css::SILVER,
-            css::SALMON,
-            css::YELLOW_GREEN,
-            css::WHITE,
-            css::RED,
-        ];
-        let color = available_colors[num_characters % available_colors.len()];
-        let angle: f32 = num_characters as f32 * 5.0;
-        let x = 2.0 * angle.cos();
-        let z = 2.0 * angle.sin();
+        let team = balanced_team(teams.iter().copied());
+        teams.push(team);
+        let position = spawn_points.select(Some(team), None, &spawn_positions);
+        spawn_positions.push(position);
 
         let character = commands
             .spawn((
                 Name::new("Character"),
                 ActionState::<CharacterAction>::default(),
-                Position(Vec3::new(x, 3.0, z)),
+                Position(position),
                 replicate,
-                CharacterPhysicsBundle::default(),
-                ColorComponent(color.into()),
+                CharacterPhysicsBundle::new(&movement_config, false),
+                ColorComponent(team.color()),
+                team,
+                CharacterController::default(),
+                AbilitiesBundle::default(),
+                PlayerId(client_id),
+                HeldProp::default(),
+                Health::new(MAX_HEALTH),
+                PositionHistory::default(),
+                ShooterViewDelay::default(),
                 CharacterMarker,
             ))
             .id();
 
-        info!("Created entity {character:?} for client {client_id:?}");
-        num_characters += 1;
+        info!("Created entity {character:?} for client {client_id:?} in team {team:?}");
     }
 }
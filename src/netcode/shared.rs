@@ -7,10 +7,9 @@ use avian3d::{
     sync::SyncPlugin,
     PhysicsPlugins,
 };
-use bevy::prelude::Dir3;
-use bevy::prelude::Res;
+use bevy::prelude::{resource_changed, IntoSystemConfigs, Query, Res, With};
 use bevy::{
-    app::{FixedUpdate, Plugin, PostUpdate},
+    app::{FixedUpdate, Plugin, PostUpdate, Update},
     color::Color,
     math::Vec3,
     prelude::{Bundle, IntoSystemSetConfigs, SystemSet},
@@ -24,13 +23,15 @@ use bevy::{
 use leafwing_input_manager::prelude::ActionState;
 use lightyear::prelude::ClientId;
 
+use crate::character::config::MovementConfig;
+use crate::character::{step_up_height, CharacterController};
+use crate::combat::health::disable_dead_characters;
 use crate::netcode::protocol::CharacterAction;
+use crate::netcode::protocol::CharacterMarker;
 use crate::netcode::protocol::ProtocolPlugin;
+use crate::netcode::replication::ReplicationModes;
 use crate::render::ZinnobreIronRenderPlugin;
 
-pub(crate) const CHARACTER_CAPSULE_RADIUS: f32 = 0.5;
-pub(crate) const CHARACTER_CAPSULE_HEIGHT: f32 = 0.5;
-
 #[derive(Bundle)]
 pub(crate) struct CharacterPhysicsBundle {
     collider: Collider,
@@ -41,10 +42,10 @@ pub(crate) struct CharacterPhysicsBundle {
     friction: Friction,
 }
 
-impl Default for CharacterPhysicsBundle {
-    fn default() -> Self {
+impl CharacterPhysicsBundle {
+    pub(crate) fn new(config: &MovementConfig, crouching: bool) -> Self {
         Self {
-            collider: Collider::capsule(CHARACTER_CAPSULE_RADIUS, CHARACTER_CAPSULE_HEIGHT),
+            collider: config.collider(crouching),
             rigid_body: RigidBody::Dynamic,
             external_force: ExternalForce::ZERO.with_persistence(false),
             external_impulse: ExternalImpulse::ZERO.with_persistence(false),
@@ -57,44 +58,6 @@ impl Default for CharacterPhysicsBundle {
     }
 }
 
-pub(crate) const FLOOR_WIDTH: f32 = 100.0;
-pub(crate) const FLOOR_HEIGHT: f32 = 1.0;
-pub(crate) const FLOOR_LENGTH: f32 = 100.0;
-
-#[derive(Bundle)]
-pub(crate) struct FloorPhysicsBundle {
-    collider: Collider,
-    rigid_body: RigidBody,
-}
-
-impl Default for FloorPhysicsBundle {
-    fn default() -> Self {
-        Self {
-            collider: Collider::cuboid(FLOOR_WIDTH, FLOOR_HEIGHT, FLOOR_LENGTH),
-            rigid_body: RigidBody::Static,
-        }
-    }
-}
-
-pub(crate) const BLOCK_WIDTH: f32 = 1.0;
-pub(crate) const BLOCK_HEIGHT: f32 = 1.0;
-pub(crate) const BLOCK_LENGTH: f32 = 1.0;
-
-#[derive(Bundle)]
-pub(crate) struct BlockPhysicsBundle {
-    collider: Collider,
-    rigid_body: RigidBody,
-}
-
-impl Default for BlockPhysicsBundle {
-    fn default() -> Self {
-        Self {
-            collider: Collider::cuboid(BLOCK_WIDTH, BLOCK_HEIGHT, BLOCK_LENGTH),
-            rigid_body: RigidBody::Dynamic,
-        }
-    }
-}
-
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum FixedSet {
     // Main fixed update systems (i.e. inputs)
@@ -104,13 +67,31 @@ pub enum FixedSet {
 }
 
 #[derive(Clone)]
-pub struct SharedPlugin;
+pub struct SharedPlugin {
+    pub replication_modes: ReplicationModes,
+    /// Debug drawing of the server's state behind predicted entities
+    pub show_confirmed: bool,
+}
 
 impl Plugin for SharedPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_plugins(ProtocolPlugin);
+        app.insert_resource(self.replication_modes);
+        app.init_resource::<MovementConfig>();
+        app.add_systems(
+            Update,
+            resize_character_colliders.run_if(resource_changed::<MovementConfig>),
+        );
+        app.add_systems(
+            FixedUpdate,
+            disable_dead_characters
+                .after(FixedSet::Main)
+                .before(FixedSet::Physics),
+        );
         if app.is_plugin_added::<RenderPlugin>() {
-            app.add_plugins(ZinnobreIronRenderPlugin);
+            app.add_plugins(ZinnobreIronRenderPlugin {
+                show_confirmed: self.show_confirmed,
+            });
         }
 
         // Physics
@@ -150,9 +131,20 @@ impl Plugin for SharedPlugin {
     }
 }
 
+/// Keep the character colliders in sync with the movement profile when it is reloaded
+fn resize_character_colliders(
+    movement_config: Res<MovementConfig>,
+    mut query: Query<(&CharacterController, &mut Collider), With<CharacterMarker>>,
+) {
+    for (controller, mut collider) in &mut query {
+        *collider = movement_config.collider(controller.crouching);
+    }
+}
+
 // Generate player color based on id
 pub(crate) fn color_from_id(client_id: ClientId) -> Color {
-    let h = (((client_id.to_bits().wrapping_mul(30)) % 360) as f32) / 360.0;
+    // Hue in degrees
+    let h = ((client_id.to_bits().wrapping_mul(30)) % 360) as f32;
     let s = 1.0;
     let l = 0.5;
     Color::hsl(h, s, l)
@@ -165,61 +157,128 @@ pub struct CharacterQuery {
     pub external_impulse: &'static mut ExternalImpulse,
     pub linear_velocity: &'static LinearVelocity,
     pub mass: &'static Mass,
-    pub position: &'static Position,
+    pub position: &'static mut Position,
+    pub collider: &'static mut Collider,
+    pub controller: &'static mut CharacterController,
     pub entity: Entity,
 }
 
 pub fn apply_character_action(
     time: &Res<Time>,
+    config: &MovementConfig,
     spatial_query: &SpatialQuery,
     action_state: &ActionState<CharacterAction>,
     character: &mut CharacterQueryItem,
 ) {
-    const MAX_SPEED: f32 = 5.5;
-    const MAX_ACCELERATION: f32 = 20.0;
+    let filter = SpatialQueryFilter::from_excluded_entities([character.entity]);
 
-    let max_velocity_delta_per_tick = MAX_ACCELERATION * time.delta_seconds();
+    // The collider isn't rolled back, only the controller is: after a rollback across a crouch
+    // the two disagree, so derive the collider from the crouch state every tick
+    let crouching = character.controller.crouching;
+    if !config.is_collider(&character.collider, crouching) {
+        *character.collider = config.collider(crouching);
+    }
 
-    if action_state.pressed(&CharacterAction::Jump) {
-        let ray_cast_origin = character.position.0
-            + Vec3::new(
-                0.0,
-                -CHARACTER_CAPSULE_HEIGHT / 2.0 - CHARACTER_CAPSULE_RADIUS,
-                0.0,
-            );
-
-        if spatial_query
-            .cast_ray(
-                ray_cast_origin,
-                Dir3::NEG_Y,
-                0.01,
-                true,
-                SpatialQueryFilter::from_excluded_entities([character.entity]),
-            )
-            .is_some()
-        {
-            character
-                .external_impulse
-                .apply_impulse(Vec3::new(0.0, 5.0, 0.0));
-        }
+    let snap = character.controller.update_ground(
+        config,
+        spatial_query,
+        character.position.0,
+        character.linear_velocity.y,
+        &filter,
+    );
+    if snap != 0.0 {
+        character.position.0.y += snap;
     }
 
+    if let Some(offset) = character.controller.update_crouch(
+        config,
+        spatial_query,
+        character.position.0,
+        action_state.pressed(&CharacterAction::Crouch),
+        &filter,
+    ) {
+        character.position.0.y += offset;
+        *character.collider = config.collider(character.controller.crouching);
+    }
+
+    if character
+        .controller
+        .try_jump(config, action_state.just_pressed(&CharacterAction::Jump))
+    {
+        // Cancel any downwards velocity so coyote jumps reach the same height
+        let fall_speed = character.linear_velocity.y.min(0.0);
+        character.external_impulse.apply_impulse(Vec3::new(
+            0.0,
+            config.jump_impulse - fall_speed * character.mass.0,
+            0.0,
+        ));
+    }
+
+    let grounded = character.controller.grounded;
+
+    let max_speed = if character.controller.crouching {
+        config.crouch_speed
+    } else if grounded && action_state.pressed(&CharacterAction::Sprint) {
+        config.sprint_speed
+    } else {
+        config.walk_speed
+    };
+    let max_acceleration = if grounded {
+        config.ground_acceleration
+    } else {
+        config.air_acceleration
+    };
+    let max_velocity_delta_per_tick = max_acceleration * time.delta_seconds();
+
     let move_dir = action_state
         .axis_pair(&CharacterAction::Move)
         .clamp_length_max(1.0);
     let move_dir = Vec3::new(-move_dir.x, 0.0, move_dir.y);
 
-    let ground_linear_velocity = Vec3::new(
-        character.linear_velocity.x,
-        0.0,
-        character.linear_velocity.z,
-    );
+    let desired_ground_linear_velocity = move_dir * max_speed;
 
-    let desired_ground_linear_velocity = move_dir * MAX_SPEED;
+    let (ground_linear_velocity, desired_ground_linear_velocity) = if grounded {
+        // Move along the ground plane so slopes don't slow us down or launch us,
+        // and so that gravity doesn't make us slide down walkable slopes.
+        let normal = character.controller.ground_normal;
+        let velocity = character.linear_velocity.0;
+        let planar_velocity = velocity - normal * velocity.dot(normal);
+        let desired = desired_ground_linear_velocity;
+        let planar_desired =
+            (desired - normal * desired.dot(normal)).normalize_or_zero() * desired.length();
+        (planar_velocity, planar_desired)
+    } else {
+        (
+            Vec3::new(
+                character.linear_velocity.x,
+                0.0,
+                character.linear_velocity.z,
+            ),
+            desired_ground_linear_velocity,
+        )
+    };
 
     let new_ground_linear_velocity = ground_linear_velocity
         .move_towards(desired_ground_linear_velocity, max_velocity_delta_per_tick);
 
+    if grounded {
+        let horizontal_motion = Vec3::new(
+            new_ground_linear_velocity.x,
+            0.0,
+            new_ground_linear_velocity.z,
+        ) * time.delta_seconds();
+        if let Some(height) = step_up_height(
+            config,
+            spatial_query,
+            &character.collider,
+            character.position.0,
+            horizontal_motion,
+            &filter,
+        ) {
+            character.position.0.y += height;
+        }
+    }
+
     let required_acceleration =
         (new_ground_linear_velocity - ground_linear_velocity) / time.delta_seconds();
 
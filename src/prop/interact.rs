@@ -0,0 +1,180 @@
+//! Picking up and throwing props.
+//!
+//! Holding the interact button grabs the prop under the character's aim and carries it in
+//! front of the character by driving its velocity towards the hold point; releasing the
+//! button throws it. This runs in the fixed update of the server and of the predicting
+//! clients, so the holding client predicts the carried prop from its own inputs while the
+//! server stays authoritative over it.
+use avian3d::prelude::{
+    AngularVelocity, ExternalImpulse, LinearVelocity, Position, SpatialQuery, SpatialQueryFilter,
+};
+use bevy::ecs::entity::{EntityMapper, MapEntities};
+use bevy::ecs::query::{QueryData, QueryFilter};
+use bevy::math::Vec3;
+use bevy::prelude::{Component, Entity, Has, Query, Res};
+use leafwing_input_manager::prelude::ActionState;
+use serde::{Deserialize, Serialize};
+
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::combat::hitscan::shot_ray;
+use crate::netcode::protocol::{CharacterAction, Dead};
+use crate::prop::Prop;
+
+/// How far away a prop can be grabbed from
+const GRAB_RANGE: f32 = 3.0;
+/// How far in front of the character's eyes a held prop is carried
+const HOLD_DISTANCE: f32 = 1.8;
+/// Heavier props can't be picked up
+const MAX_GRAB_MASS: f32 = 10.0;
+/// How fast a held prop catches up with the hold point, per second
+const HOLD_STIFFNESS: f32 = 15.0;
+const MAX_HOLD_SPEED: f32 = 20.0;
+/// A held prop further than this from the hold point (stuck behind a wall) is dropped
+const HOLD_BREAK_DISTANCE: f32 = 2.5;
+/// Impulse given to a prop when it is thrown, tilted slightly upwards
+const THROW_IMPULSE: f32 = 8.0;
+const THROW_LIFT: f32 = 0.2;
+
+/// The prop a character is carrying, if any
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+pub struct HeldProp(pub Option<Entity>);
+
+impl MapEntities for HeldProp {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        if let Some(entity) = &mut self.0 {
+            *entity = entity_mapper.map_entity(*entity);
+        }
+    }
+}
+
+#[derive(QueryData)]
+#[query_data(mutable)]
+pub(crate) struct CarriedPropQuery {
+    pub(crate) prop: &'static Prop,
+    pub(crate) position: &'static Position,
+    pub(crate) linear_velocity: &'static mut LinearVelocity,
+    pub(crate) angular_velocity: &'static mut AngularVelocity,
+    pub(crate) external_impulse: &'static mut ExternalImpulse,
+}
+
+/// The character doing the interaction
+struct Interactor<'a> {
+    entity: Entity,
+    position: Vec3,
+    controller: &'a CharacterController,
+    dead: bool,
+}
+
+/// Run the interactions of every character matching `F` for this tick
+pub(crate) fn handle_interactions<F: QueryFilter>(
+    movement_config: Res<MovementConfig>,
+    spatial_query: SpatialQuery,
+    mut character_query: Query<
+        (
+            Entity,
+            &ActionState<CharacterAction>,
+            &Position,
+            &CharacterController,
+            &mut HeldProp,
+            Has<Dead>,
+        ),
+        F,
+    >,
+    mut prop_query: Query<CarriedPropQuery>,
+) {
+    let mut held: Vec<Entity> = character_query
+        .iter()
+        .filter_map(|(_, _, _, _, held_prop, _)| held_prop.0)
+        .collect();
+    for (entity, action_state, position, controller, mut held_prop, dead) in &mut character_query {
+        let previous = held_prop.0;
+        let held_by_others: Vec<Entity> = held
+            .iter()
+            .copied()
+            .filter(|prop| Some(*prop) != previous)
+            .collect();
+        apply_interaction(
+            &movement_config,
+            &spatial_query,
+            action_state,
+            Interactor {
+                entity,
+                position: position.0,
+                controller,
+                dead,
+            },
+            &mut held_prop,
+            &held_by_others,
+            &mut prop_query,
+        );
+        if let Some(prop) = held_prop.0.filter(|_| previous.is_none()) {
+            held.push(prop);
+        }
+    }
+}
+
+/// Grab, carry or throw a prop for one tick.
+///
+/// `held_by_others` are the props already carried by other characters, they can't be grabbed.
+fn apply_interaction(
+    config: &MovementConfig,
+    spatial_query: &SpatialQuery,
+    action_state: &ActionState<CharacterAction>,
+    interactor: Interactor,
+    held_prop: &mut HeldProp,
+    held_by_others: &[Entity],
+    prop_query: &mut Query<CarriedPropQuery>,
+) {
+    let (eye, direction) = shot_ray(
+        config,
+        interactor.controller,
+        interactor.position,
+        action_state,
+    );
+    let wants_hold = action_state.pressed(&CharacterAction::Interact) && !interactor.dead;
+
+    let Some(prop_entity) = held_prop.0 else {
+        if !wants_hold {
+            return;
+        }
+        // Try to grab the prop we are aiming at
+        let filter = SpatialQueryFilter::from_excluded_entities([interactor.entity]);
+        let Some(hit) = spatial_query.cast_ray(eye, direction, GRAB_RANGE, true, filter) else {
+            return;
+        };
+        let grabbable = !held_by_others.contains(&hit.entity)
+            && prop_query
+                .get(hit.entity)
+                .is_ok_and(|prop| prop.prop.mass <= MAX_GRAB_MASS);
+        if grabbable {
+            held_prop.0 = Some(hit.entity);
+        }
+        return;
+    };
+
+    // The prop may have been despawned, e.g. by a level change
+    let Ok(mut prop) = prop_query.get_mut(prop_entity) else {
+        held_prop.0 = None;
+        return;
+    };
+
+    if !wants_hold {
+        held_prop.0 = None;
+        if !interactor.dead {
+            let throw_direction = (*direction + Vec3::Y * THROW_LIFT).normalize();
+            prop.external_impulse
+                .apply_impulse(throw_direction * THROW_IMPULSE);
+        }
+        return;
+    }
+
+    let hold_point = eye + direction * HOLD_DISTANCE;
+    let offset = hold_point - prop.position.0;
+    if offset.length() > HOLD_BREAK_DISTANCE {
+        held_prop.0 = None;
+        return;
+    }
+    prop.linear_velocity.0 = (offset * HOLD_STIFFNESS).clamp_length_max(MAX_HOLD_SPEED);
+    prop.angular_velocity.0 *= 0.5;
+}
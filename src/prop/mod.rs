@@ -0,0 +1,111 @@
+//! Dynamic physics props.
+//!
+//! A prop is fully described by its replicated [`Prop`] component: the server spawns it from
+//! the level file and every client builds the matching collider and mesh from it.
+pub(crate) mod interact;
+
+use avian3d::prelude::{
+    Collider, ColliderDensity, ExternalImpulse, Friction, Restitution, RigidBody,
+};
+use bevy::log::warn;
+use bevy::math::Vec3;
+use bevy::prelude::{Bundle, Color, Component};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum PropShape {
+    Cuboid {
+        size: Vec3,
+    },
+    Sphere {
+        radius: f32,
+    },
+    /// `height` is the length of the cylindrical part
+    Capsule {
+        radius: f32,
+        height: f32,
+    },
+    /// Convex hull of the given points
+    ConvexHull {
+        points: Vec<Vec3>,
+    },
+}
+
+#[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Prop {
+    pub shape: PropShape,
+    pub mass: f32,
+    pub friction: f32,
+    pub restitution: f32,
+    /// sRGB color
+    pub color: [f32; 3],
+}
+
+impl Prop {
+    pub fn collider(&self) -> Collider {
+        match &self.shape {
+            PropShape::Cuboid { size } => Collider::cuboid(size.x, size.y, size.z),
+            PropShape::Sphere { radius } => Collider::sphere(*radius),
+            PropShape::Capsule { radius, height } => Collider::capsule(*radius, *height),
+            PropShape::ConvexHull { points } => Collider::convex_hull(points.clone())
+                .unwrap_or_else(|| {
+                    warn!("Invalid convex hull for prop, using a unit cube instead");
+                    Collider::cuboid(1.0, 1.0, 1.0)
+                }),
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        Color::srgb(self.color[0], self.color[1], self.color[2])
+    }
+
+    /// Check that the prop can be simulated: a positive mass and a shape with a volume
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.mass.is_finite() || self.mass <= 0.0 {
+            return Err(format!("mass must be positive, got {}", self.mass));
+        }
+        if let PropShape::ConvexHull { points } = &self.shape {
+            let Some(collider) = Collider::convex_hull(points.clone()) else {
+                return Err("convex hull can't be built from its points".to_string());
+            };
+            let unit_mass = collider.mass_properties(1.0).mass.0;
+            if !unit_mass.is_finite() || unit_mass <= 0.0 {
+                return Err("convex hull has no volume".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Bundle)]
+pub(crate) struct PropPhysicsBundle {
+    collider: Collider,
+    density: ColliderDensity,
+    rigid_body: RigidBody,
+    external_impulse: ExternalImpulse,
+    friction: Friction,
+    restitution: Restitution,
+}
+
+impl PropPhysicsBundle {
+    pub(crate) fn new(prop: &Prop) -> Self {
+        let collider = prop.collider();
+        // The collider gives the body its mass, so pick the density that adds up to `prop.mass`
+        let unit_mass = collider.mass_properties(1.0).mass.0;
+        let density = if unit_mass > 0.0 {
+            prop.mass / unit_mass
+        } else {
+            // Levels are validated when loaded, this only guards against a division by zero
+            warn!("Prop collider has no volume, using a density of 1");
+            1.0
+        };
+        Self {
+            collider,
+            density: ColliderDensity(density),
+            rigid_body: RigidBody::Dynamic,
+            external_impulse: ExternalImpulse::ZERO.with_persistence(false),
+            friction: Friction::new(prop.friction),
+            restitution: Restitution::new(prop.restitution),
+        }
+    }
+}
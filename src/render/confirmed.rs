@@ -0,0 +1,131 @@
+//! Debug view of the server's state: the Confirmed entity behind each predicted character and
+//! prop is drawn as a translucent wireframe, with a line to where we predict it to be.
+//! Interpolated entities are left alone, they are already drawn where the server had them.
+use avian3d::prelude::{Collider, Position, Rotation};
+use bevy::color::Color;
+use bevy::input::ButtonInput;
+use bevy::log::info;
+use bevy::math::primitives::{Capsule3d, Cuboid, Sphere};
+use bevy::math::Vec3;
+use bevy::prelude::{Gizmos, KeyCode, Query, Res, ResMut, Resource, With};
+use lightyear::prelude::client::Confirmed;
+
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::netcode::protocol::CharacterMarker;
+use crate::prop::{Prop, PropShape};
+
+/// Key toggling the confirmed ghosts
+const TOGGLE_KEY: KeyCode = KeyCode::F3;
+const GHOST_COLOR: Color = Color::srgba(0.3, 0.9, 1.0, 0.5);
+const ERROR_COLOR: Color = Color::srgba(1.0, 0.3, 0.3, 0.8);
+
+/// Whether the confirmed ghosts are drawn
+#[derive(Resource, Clone, Copy, Debug)]
+pub(crate) struct ShowConfirmed(pub(crate) bool);
+
+pub(crate) fn toggle_show_confirmed(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut show_confirmed: ResMut<ShowConfirmed>,
+) {
+    if keys.just_pressed(TOGGLE_KEY) {
+        show_confirmed.0 = !show_confirmed.0;
+        info!(enabled = show_confirmed.0, "Toggled confirmed ghosts");
+    }
+}
+
+pub(crate) fn draw_confirmed_characters(
+    mut gizmos: Gizmos,
+    movement_config: Res<MovementConfig>,
+    confirmed_query: Query<
+        (&Confirmed, &Position, &Rotation, &CharacterController),
+        With<CharacterMarker>,
+    >,
+    position_query: Query<&Position>,
+) {
+    for (confirmed, position, rotation, controller) in &confirmed_query {
+        // Interpolated entities already show the server's state
+        if confirmed.predicted.is_none() {
+            continue;
+        }
+        let capsule = Capsule3d::new(
+            movement_config.capsule_radius,
+            movement_config.capsule_height(controller.crouching),
+        );
+        gizmos.primitive_3d(&capsule, position.0, rotation.0, GHOST_COLOR);
+        draw_prediction_error(&mut gizmos, confirmed, position, &position_query);
+    }
+}
+
+pub(crate) fn draw_confirmed_props(
+    mut gizmos: Gizmos,
+    confirmed_query: Query<(&Confirmed, &Position, &Rotation, &Prop)>,
+    position_query: Query<&Position>,
+) {
+    for (confirmed, position, rotation, prop) in &confirmed_query {
+        if confirmed.predicted.is_none() {
+            continue;
+        }
+        match &prop.shape {
+            PropShape::Cuboid { size } => gizmos.primitive_3d(
+                &Cuboid::from_size(*size),
+                position.0,
+                rotation.0,
+                GHOST_COLOR,
+            ),
+            PropShape::Sphere { radius } => {
+                gizmos.primitive_3d(&Sphere::new(*radius), position.0, rotation.0, GHOST_COLOR)
+            }
+            PropShape::Capsule { radius, height } => gizmos.primitive_3d(
+                &Capsule3d::new(*radius, *height),
+                position.0,
+                rotation.0,
+                GHOST_COLOR,
+            ),
+            PropShape::ConvexHull { .. } => {
+                draw_convex_hull(&mut gizmos, &prop.collider(), position, rotation)
+            }
+        }
+        draw_prediction_error(&mut gizmos, confirmed, position, &position_query);
+    }
+}
+
+/// Edges of a convex hull collider
+fn draw_convex_hull(
+    gizmos: &mut Gizmos,
+    collider: &Collider,
+    position: &Position,
+    rotation: &Rotation,
+) {
+    let Some(hull) = collider.shape().as_convex_polyhedron() else {
+        return;
+    };
+    let points = hull.points();
+    let world_point = |index: u32| {
+        let point = points[index as usize];
+        position.0 + rotation.0 * Vec3::new(point.x, point.y, point.z)
+    };
+    for edge in hull.edges() {
+        gizmos.line(
+            world_point(edge.vertices[0]),
+            world_point(edge.vertices[1]),
+            GHOST_COLOR,
+        );
+    }
+}
+
+/// Line from the server's position to our predicted one
+fn draw_prediction_error(
+    gizmos: &mut Gizmos,
+    confirmed: &Confirmed,
+    confirmed_position: &Position,
+    position_query: &Query<&Position>,
+) {
+    let Some(predicted_position) = confirmed
+        .predicted
+        .and_then(|predicted| position_query.get(predicted).ok())
+    else {
+        return;
+    };
+    gizmos.line(confirmed_position.0, predicted_position.0, ERROR_COLOR);
+}
@@ -1,11 +1,25 @@
-use crate::netcode::protocol::{BlockMarker, CharacterMarker, ColorComponent, FloorMarker};
-use crate::netcode::shared::{
-    BLOCK_HEIGHT, BLOCK_LENGTH, BLOCK_WIDTH, CHARACTER_CAPSULE_HEIGHT, CHARACTER_CAPSULE_RADIUS,
-    FLOOR_HEIGHT, FLOOR_LENGTH, FLOOR_WIDTH,
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::combat::hitscan::Tracer;
+use crate::combat::projectile::PROJECTILE_RADIUS;
+use crate::level::{LevelGeometry, LevelLight};
+use crate::netcode::mispredictions::MispredictionStats;
+use crate::netcode::protocol::{
+    Ability, CharacterMarker, ColorComponent, Dead, Health, Projectile,
 };
+use crate::prop::{Prop, PropShape};
+use crate::team::Team;
 use avian3d::prelude::{Position, Rotation};
 use bevy::color::Color;
+use bevy::gizmos::AppGizmoBuilder;
 use bevy::prelude::Cuboid;
+use bevy::prelude::Or;
+use bevy::prelude::Sphere;
+use bevy::prelude::{Gizmos, Has, PositionType, Quat, Style, Text, TextBundle, Val, Visibility};
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::text::TextStyle;
+use bevy::time::Time;
 use bevy::{
     app::{Plugin, Startup, Update},
     asset::Assets,
@@ -13,31 +27,67 @@ use bevy::{
     math::{Dir3, Vec3},
     pbr::{PbrBundle, PointLight, PointLightBundle, StandardMaterial},
     prelude::{
-        default, Added, Camera3dBundle, Capsule3d, Commands, Component, Entity, Mesh, OnAdd, Query,
-        ResMut, Transform, Trigger, With, Without,
+        default, resource_changed, Added, Camera3dBundle, Capsule3d, Commands, Component, Entity,
+        Handle, IntoSystemConfigs, Mesh, OnAdd, Query, Res, ResMut, Resource, Transform, Trigger,
+        With, Without,
     },
 };
 use bevy_screen_diagnostics::{
     Aggregate, ScreenDiagnostics, ScreenDiagnosticsPlugin, ScreenEntityDiagnosticsPlugin,
 };
-use lightyear::prelude::Replicated;
+use confirmed::{
+    draw_confirmed_characters, draw_confirmed_props, toggle_show_confirmed, ShowConfirmed,
+};
+use leafwing_abilities::prelude::{ChargeState, CooldownState};
+use lightyear::shared::replication::components::Controlled;
 use lightyear::{
     client::prediction::diagnostics::PredictionDiagnosticsPlugin,
-    prelude::client::{Confirmed, Predicted, VisualInterpolateStatus, VisualInterpolationPlugin},
+    prelude::client::{
+        Confirmed, Interpolated, Predicted, VisualInterpolateStatus, VisualInterpolationPlugin,
+    },
     transport::io::IoDiagnosticsPlugin,
 };
+use netgraph::{draw_netgraph, setup_netgraph, toggle_netgraph, NetGraphGizmos, ShowNetGraph};
+
+mod confirmed;
+mod netgraph;
 
-pub struct ZinnobreIronRenderPlugin;
+pub struct ZinnobreIronRenderPlugin {
+    /// Draw the confirmed ghosts on startup, they can be toggled at runtime
+    pub show_confirmed: bool,
+}
 
 impl Plugin for ZinnobreIronRenderPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_systems(Startup, init);
+        app.insert_resource(ShowConfirmed(self.show_confirmed));
+        app.init_gizmo_group::<NetGraphGizmos>();
+        app.init_resource::<ShowNetGraph>();
+        app.add_systems(Startup, setup_netgraph);
+        app.add_systems(Update, (toggle_netgraph, draw_netgraph).chain());
+        app.add_systems(
+            Update,
+            (
+                toggle_show_confirmed,
+                (draw_confirmed_characters, draw_confirmed_props)
+                    .run_if(|show_confirmed: Res<ShowConfirmed>| show_confirmed.0),
+            )
+                .chain(),
+        );
         app.add_systems(
             Update,
             (
                 add_character_cosmetics,
-                add_floor_cosmetics,
-                add_block_cosmetics,
+                update_character_crouch_mesh,
+                rebuild_character_meshes.run_if(resource_changed::<MovementConfig>),
+                add_level_cosmetics,
+                add_level_lights,
+                add_prop_cosmetics,
+                add_projectile_cosmetics,
+                update_hud,
+                update_misprediction_table,
+                hide_dead_characters,
+                draw_tracers,
             ),
         );
 
@@ -52,20 +102,80 @@ impl Plugin for ZinnobreIronRenderPlugin {
     }
 }
 
-fn init(mut commands: Commands, mut onscreen: ResMut<ScreenDiagnostics>) {
+/// Capsule meshes for the standing and crouching character, shared by all characters
+#[derive(Resource)]
+struct CharacterMeshes {
+    standing: Handle<Mesh>,
+    crouching: Handle<Mesh>,
+}
+
+impl CharacterMeshes {
+    fn get(&self, crouching: bool) -> &Handle<Mesh> {
+        if crouching {
+            &self.crouching
+        } else {
+            &self.standing
+        }
+    }
+}
+
+fn init(
+    mut commands: Commands,
+    mut onscreen: ResMut<ScreenDiagnostics>,
+    movement_config: Res<MovementConfig>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    commands.insert_resource(CharacterMeshes {
+        standing: meshes.add(Capsule3d::new(
+            movement_config.capsule_radius,
+            movement_config.capsule_height(false),
+        )),
+        crouching: meshes.add(Capsule3d::new(
+            movement_config.capsule_radius,
+            movement_config.capsule_height(true),
+        )),
+    });
+
     commands.spawn(Camera3dBundle {
         transform: Transform::from_xyz(0.0, 4.5, -9.0).looking_at(Vec3::ZERO, Dir3::Y),
         ..default()
     });
 
-    commands.spawn(PointLightBundle {
-        point_light: PointLight {
-            shadows_enabled: true,
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
             ..default()
-        },
-        transform: Transform::from_xyz(4.0, 8.0, 4.0),
-        ..default()
-    });
+        }),
+        Hud,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            right: Val::Px(10.0),
+            ..default()
+        }),
+        MispredictionTable,
+    ));
 
     onscreen
         .add("RB".to_string(), PredictionDiagnosticsPlugin::ROLLBACKS)
@@ -96,9 +206,147 @@ fn init(mut commands: Commands, mut onscreen: ResMut<ScreenDiagnostics>) {
         .format(|v| format!("{v:0>3.0}"));
 }
 
+/// Text showing the health and ability cooldowns of the local character
+#[derive(Component)]
+struct Hud;
+
+/// Text listing the entities and components that mispredicted the most
+#[derive(Component)]
+struct MispredictionTable;
+
+/// Rows shown in the misprediction table
+const MISPREDICTION_TABLE_ROWS: usize = 10;
+
+fn update_misprediction_table(
+    stats: Option<Res<MispredictionStats>>,
+    mut table_query: Query<&mut Text, With<MispredictionTable>>,
+) {
+    let Ok(mut text) = table_query.get_single_mut() else {
+        return;
+    };
+    let rows = stats
+        .map(|stats| stats.worst(MISPREDICTION_TABLE_ROWS))
+        .unwrap_or_default();
+    if rows.is_empty() {
+        text.sections[0].value.clear();
+        return;
+    }
+    let mut table = String::from("entity     component        count      max     last\n");
+    for (entity, component, errors) in rows {
+        table.push_str(&format!(
+            "{:<10} {:<15} {:>6} {:>8.4} {:>8.4}\n",
+            entity.to_string(),
+            component.to_string(),
+            errors.count,
+            errors.max,
+            errors.last
+        ));
+    }
+    text.sections[0].value = table;
+}
+
+fn update_hud(
+    character_query: Query<
+        (
+            &Health,
+            Option<&Team>,
+            Option<&Dead>,
+            &CooldownState<Ability>,
+            Option<&ChargeState<Ability>>,
+        ),
+        (With<Predicted>, With<Controlled>),
+    >,
+    mut hud_query: Query<&mut Text, With<Hud>>,
+) {
+    let Ok(mut text) = hud_query.get_single_mut() else {
+        return;
+    };
+    let Ok((health, team, dead, cooldowns, charges)) = character_query.get_single() else {
+        text.sections[0].value.clear();
+        return;
+    };
+
+    let mut hud = format!("HP {:.0}/{:.0}\n", health.current, health.max);
+    if let Some(team) = team {
+        hud.push_str(&format!("Team {team:?}\n"));
+    }
+    if dead.is_some() {
+        hud.push_str("Dead, respawning...\n");
+    }
+    for ability in Ability::ALL {
+        hud.push_str(&format!("{ability:?}"));
+        if let Some(charges) = charges.and_then(|charges| charges.get(&ability)) {
+            hud.push_str(&format!(" {}/{}", charges.charges(), charges.max_charges()));
+        }
+        if let Some(cooldown) = cooldowns.get(&ability) {
+            let remaining = cooldown.remaining().as_secs_f32();
+            if remaining > 0.0 {
+                hud.push_str(&format!(" {remaining:.1}s"));
+            }
+        }
+        hud.push('\n');
+    }
+    text.sections[0].value = hud;
+}
+
+fn hide_dead_characters(
+    mut character_query: Query<
+        (Has<Dead>, &mut Visibility),
+        (With<CharacterMarker>, Without<Confirmed>),
+    >,
+) {
+    for (is_dead, mut visibility) in &mut character_query {
+        let wanted = if is_dead {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
+
+fn draw_tracers(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut gizmos: Gizmos,
+    mut tracer_query: Query<(Entity, &mut Tracer)>,
+) {
+    for (entity, mut tracer) in &mut tracer_query {
+        if tracer.timer.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let color = if tracer.hit {
+            Color::srgb(1.0, 0.1, 0.1)
+        } else {
+            Color::srgb(1.0, 0.9, 0.5)
+        };
+        gizmos.line(tracer.start, tracer.end, color);
+        // Muzzle flash for the first part of the tracer's life
+        if tracer.timer.fraction() < 0.3 {
+            gizmos.sphere(
+                tracer.start,
+                Quat::IDENTITY,
+                0.1,
+                Color::srgb(1.0, 0.8, 0.2),
+            );
+        }
+    }
+}
+
 fn add_visual_interpolation_components<T: Component>(
     trigger: Trigger<OnAdd, T>,
-    query: Query<Entity, (With<T>, Without<Confirmed>, Without<FloorMarker>)>,
+    query: Query<
+        Entity,
+        (
+            With<T>,
+            Without<Confirmed>,
+            Without<Interpolated>,
+            Without<LevelGeometry>,
+        ),
+    >,
     mut commands: Commands,
 ) {
     if !query.contains(trigger.entity()) {
@@ -115,50 +363,148 @@ fn add_visual_interpolation_components<T: Component>(
 
 fn add_character_cosmetics(
     mut commands: Commands,
-    character_query: Query<(Entity, &ColorComponent), (Added<Predicted>, With<CharacterMarker>)>,
-    mut meshes: ResMut<Assets<Mesh>>,
+    character_query: Query<
+        (Entity, &ColorComponent, &CharacterController),
+        (
+            Or<(Added<Predicted>, Added<Interpolated>)>,
+            With<CharacterMarker>,
+        ),
+    >,
+    character_meshes: Res<CharacterMeshes>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    for (entity, color) in &character_query {
+    for (entity, color, controller) in &character_query {
         info!(?entity, "Adding cosmetics to character {:?}", entity);
         commands.entity(entity).insert((PbrBundle {
-            mesh: meshes.add(Capsule3d::new(
-                CHARACTER_CAPSULE_RADIUS,
-                CHARACTER_CAPSULE_HEIGHT,
-            )),
+            mesh: character_meshes.get(controller.crouching).clone(),
             material: materials.add(color.0),
             ..default()
         },));
     }
 }
 
-fn add_floor_cosmetics(
+fn rebuild_character_meshes(
+    movement_config: Res<MovementConfig>,
+    character_meshes: Res<CharacterMeshes>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    for crouching in [false, true] {
+        meshes.insert(
+            character_meshes.get(crouching),
+            Capsule3d::new(
+                movement_config.capsule_radius,
+                movement_config.capsule_height(crouching),
+            )
+            .into(),
+        );
+    }
+}
+
+fn update_character_crouch_mesh(
+    mut character_query: Query<
+        (&CharacterController, &mut Handle<Mesh>),
+        (
+            Or<(With<Predicted>, With<Interpolated>)>,
+            With<CharacterMarker>,
+        ),
+    >,
+    character_meshes: Res<CharacterMeshes>,
+) {
+    for (controller, mut mesh) in &mut character_query {
+        let wanted = character_meshes.get(controller.crouching);
+        if *mesh != *wanted {
+            *mesh = wanted.clone();
+        }
+    }
+}
+
+fn add_level_cosmetics(
     mut commands: Commands,
-    floor_query: Query<Entity, (Added<Replicated>, With<FloorMarker>)>,
+    geometry_query: Query<(Entity, &LevelGeometry), Added<LevelGeometry>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    for entity in &floor_query {
-        info!(?entity, "Adding cosmetics to floor {:?}", entity);
+    for (entity, geometry) in &geometry_query {
         commands.entity(entity).insert(PbrBundle {
-            mesh: meshes.add(Cuboid::new(FLOOR_WIDTH, FLOOR_HEIGHT, FLOOR_LENGTH)),
-            material: materials.add(Color::srgb(1.0, 1.0, 1.0)),
+            mesh: meshes.add(Cuboid::from_size(geometry.size)),
+            material: materials.add(geometry.color()),
+            transform: Transform::from_translation(geometry.position),
             ..default()
         });
     }
 }
 
-fn add_block_cosmetics(
+fn add_level_lights(
+    mut commands: Commands,
+    light_query: Query<(Entity, &LevelLight), Added<LevelLight>>,
+) {
+    for (entity, light) in &light_query {
+        commands.entity(entity).insert(PointLightBundle {
+            point_light: PointLight {
+                intensity: light.intensity,
+                shadows_enabled: light.shadows,
+                ..default()
+            },
+            transform: Transform::from_translation(light.position),
+            ..default()
+        });
+    }
+}
+
+fn add_prop_cosmetics(
+    mut commands: Commands,
+    prop_query: Query<(Entity, &Prop), Or<(Added<Predicted>, Added<Interpolated>)>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, prop) in &prop_query {
+        info!(?entity, "Adding cosmetics to prop {:?}", entity);
+        commands.entity(entity).insert(PbrBundle {
+            mesh: meshes.add(prop_mesh(prop)),
+            material: materials.add(prop.color()),
+            ..default()
+        });
+    }
+}
+
+fn prop_mesh(prop: &Prop) -> Mesh {
+    match &prop.shape {
+        PropShape::Cuboid { size } => Cuboid::from_size(*size).into(),
+        PropShape::Sphere { radius } => Sphere::new(*radius).into(),
+        PropShape::Capsule { radius, height } => Capsule3d::new(*radius, *height).into(),
+        PropShape::ConvexHull { .. } => {
+            let collider = prop.collider();
+            let Some(polyhedron) = collider.shape().as_convex_polyhedron() else {
+                return Cuboid::from_length(1.0).into();
+            };
+            let (vertices, triangles) = polyhedron.to_trimesh();
+            let positions: Vec<[f32; 3]> = vertices.iter().map(|v| [v.x, v.y, v.z]).collect();
+            let mut mesh = Mesh::new(
+                PrimitiveTopology::TriangleList,
+                RenderAssetUsages::default(),
+            )
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+            .with_inserted_indices(Indices::U32(triangles.into_iter().flatten().collect()));
+            // Flat shading needs one vertex per face corner
+            mesh.duplicate_vertices();
+            mesh.compute_flat_normals();
+            mesh
+        }
+    }
+}
+
+fn add_projectile_cosmetics(
     mut commands: Commands,
-    block_query: Query<Entity, (Added<Predicted>, With<BlockMarker>)>,
+    // Includes the projectiles we pre-spawned ourselves, which are not Predicted yet
+    projectile_query: Query<Entity, (Added<Projectile>, Without<Confirmed>)>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    for entity in &block_query {
-        info!(?entity, "Adding cosmetics to block {:?}", entity);
+    for entity in &projectile_query {
+        debug!(?entity, "Adding cosmetics to projectile {:?}", entity);
         commands.entity(entity).insert(PbrBundle {
-            mesh: meshes.add(Cuboid::new(BLOCK_WIDTH, BLOCK_HEIGHT, BLOCK_LENGTH)),
-            material: materials.add(Color::srgb(1.0, 0.0, 1.0)),
+            mesh: meshes.add(Sphere::new(PROJECTILE_RADIUS)),
+            material: materials.add(Color::srgb(1.0, 0.5, 0.0)),
             ..default()
         });
     }
@@ -0,0 +1,161 @@
+//! Netgraph overlay: line graphs of the connection statistics, toggled with F2.
+//!
+//! The graphs are drawn with gizmos on a plane just in front of the camera, so they stay
+//! fixed on screen; a dedicated gizmo group draws them on top of the scene.
+use bevy::color::Color;
+use bevy::gizmos::config::{GizmoConfigGroup, GizmoConfigStore};
+use bevy::input::ButtonInput;
+use bevy::math::{Vec2, Vec3};
+use bevy::prelude::{
+    default, Camera, Commands, Component, Gizmos, GlobalTransform, KeyCode, PositionType, Query,
+    Reflect, Res, ResMut, Resource, Style, Text, TextBundle, Val, Visibility, With,
+};
+use bevy::text::TextStyle;
+
+use crate::netcode::netgraph::{NetGraph, NetGraphSeries, NETGRAPH_SAMPLES};
+
+const TOGGLE_KEY: KeyCode = KeyCode::F2;
+/// Screen position of the top left corner of the first graph, in logical pixels
+const ORIGIN: Vec2 = Vec2::new(10.0, 10.0);
+const GRAPH_SIZE: Vec2 = Vec2::new(220.0, 36.0);
+/// Vertical space taken by a graph and its label
+const ROW_HEIGHT: f32 = 56.0;
+/// How far in front of the camera the graphs are drawn
+const DRAW_DISTANCE: f32 = 0.5;
+const LINE_COLOR: Color = Color::srgb(0.2, 1.0, 0.4);
+const FRAME_COLOR: Color = Color::srgba(1.0, 1.0, 1.0, 0.3);
+
+#[derive(Default, Reflect, GizmoConfigGroup)]
+pub(crate) struct NetGraphGizmos;
+
+#[derive(Resource, Default)]
+pub(crate) struct ShowNetGraph(bool);
+
+/// Label of one graph
+#[derive(Component)]
+pub(crate) struct NetGraphLabel(NetGraphSeries);
+
+pub(crate) fn setup_netgraph(mut commands: Commands, mut config_store: ResMut<GizmoConfigStore>) {
+    let (config, _) = config_store.config_mut::<NetGraphGizmos>();
+    // Draw over everything
+    config.depth_bias = -1.0;
+
+    for (row, series) in NetGraphSeries::ALL.into_iter().enumerate() {
+        commands.spawn((
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 14.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(ORIGIN.x),
+                top: Val::Px(ORIGIN.y + row as f32 * ROW_HEIGHT),
+                ..default()
+            }),
+            NetGraphLabel(series),
+            Visibility::Hidden,
+        ));
+    }
+}
+
+pub(crate) fn toggle_netgraph(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut show: ResMut<ShowNetGraph>,
+    mut label_query: Query<&mut Visibility, With<NetGraphLabel>>,
+) {
+    if !keys.just_pressed(TOGGLE_KEY) {
+        return;
+    }
+    show.0 = !show.0;
+    for mut visibility in &mut label_query {
+        *visibility = if show.0 {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+pub(crate) fn draw_netgraph(
+    show: Res<ShowNetGraph>,
+    netgraph: Option<Res<NetGraph>>,
+    mut gizmos: Gizmos<NetGraphGizmos>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    mut label_query: Query<(&mut Text, &NetGraphLabel)>,
+) {
+    let Some(netgraph) = netgraph.filter(|_| show.0) else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = camera_query.get_single() else {
+        return;
+    };
+    // Screen position to a point on the plane in front of the camera
+    let to_world = |screen: Vec2| -> Option<Vec3> {
+        camera
+            .viewport_to_world(camera_transform, screen)
+            .map(|ray| ray.get_point(DRAW_DISTANCE))
+    };
+
+    for (mut text, label) in &mut label_query {
+        let samples = netgraph.series.get(&label.0);
+        let current = samples.and_then(|samples| samples.back()).copied();
+        let max = samples
+            .map(|samples| samples.iter().copied().fold(0.0, f32::max))
+            .unwrap_or_default()
+            .max(1.0);
+        text.sections[0].value = format!(
+            "{} {:.1} (max {:.1})",
+            label.0.label(),
+            current.unwrap_or_default(),
+            max
+        );
+        let channels = match label.0 {
+            NetGraphSeries::BytesIn => netgraph.channels_in.as_slice(),
+            NetGraphSeries::BytesOut => netgraph.channels_out.as_slice(),
+            _ => &[],
+        };
+        for (channel, rate) in channels {
+            text.sections[0].value += &format!(" | {channel} {rate:.1}");
+        }
+
+        let row = NetGraphSeries::ALL
+            .iter()
+            .position(|series| *series == label.0)
+            .unwrap_or_default();
+        let top_left = ORIGIN + Vec2::new(0.0, row as f32 * ROW_HEIGHT + 16.0);
+        let corners = [
+            top_left,
+            top_left + Vec2::new(GRAPH_SIZE.x, 0.0),
+            top_left + GRAPH_SIZE,
+            top_left + Vec2::new(0.0, GRAPH_SIZE.y),
+            top_left,
+        ];
+        if let Some(frame) = corners
+            .into_iter()
+            .map(to_world)
+            .collect::<Option<Vec<_>>>()
+        {
+            gizmos.linestrip(frame, FRAME_COLOR);
+        }
+
+        let Some(samples) = samples else {
+            continue;
+        };
+        let step = GRAPH_SIZE.x / (NETGRAPH_SAMPLES - 1) as f32;
+        let points: Option<Vec<Vec3>> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let height = (value / max).clamp(0.0, 1.0) * GRAPH_SIZE.y;
+                to_world(top_left + Vec2::new(i as f32 * step, GRAPH_SIZE.y - height))
+            })
+            .collect();
+        if let Some(points) = points {
+            gizmos.linestrip(points, LINE_COLOR);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Deterministic replay of a session recorded by the server
+//!
+//! `replay --file <recording>` rebuilds the recorded level in a headless app without any
+//! connection, then feeds the recorded inputs to the server's character, ability and
+//! interaction systems tick by tick. The characters' positions are compared with the
+//! checkpoints the server wrote, so a divergence shows up as soon as the replay stops
+//! reproducing the session.
+//!
+//! Damage is not replayed, only the deaths and respawns it caused: hitscan shots don't move
+//! anything, and projectiles are simulated but their hits are ignored.
+use std::collections::VecDeque;
+use std::fs;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use avian3d::prelude::{AngularVelocity, LinearVelocity, Position};
+use bevy::app::{FixedUpdate, Startup};
+use bevy::asset::ron;
+use bevy::core::Name;
+use bevy::log::{info, warn};
+use bevy::math::Vec3;
+use bevy::prelude::{
+    default, Commands, Entity, IntoSystemConfigs, Query, Res, ResMut, Resource, With,
+};
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::HashMap;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::{ClientId, Tick};
+use lightyear::server::config::ServerConfig;
+use lightyear::server::plugin::ServerPlugins;
+use lightyear::shared::config::Mode;
+
+use crate::abilities::AbilitiesBundle;
+use crate::app::harness::{headless_app, init_logging};
+use crate::app::shared::{shared_config, FIXED_TIMESTEP_HZ};
+use crate::character::config::MovementConfig;
+use crate::character::CharacterController;
+use crate::combat::hitscan::HitscanShot;
+use crate::combat::projectile::handle_projectiles;
+use crate::combat::DamageEvent;
+use crate::level::{
+    despawn_level, load_level, spawn_level_geometry, CurrentLevel, LevelDefinition, LevelEntity,
+};
+use crate::netcode::protocol::{Ability, CharacterAction, CharacterMarker, Dead, PlayerId};
+use crate::netcode::recording::{RecordedEvent, RecordingHeader};
+use crate::netcode::server::{handle_ability_actions, handle_character_actions};
+use crate::netcode::shared::{CharacterPhysicsBundle, FixedSet};
+use crate::prop::interact::{handle_interactions, HeldProp};
+use crate::prop::PropPhysicsBundle;
+use crate::ZinnobreIronSettings;
+
+/// Largest distance between a replayed character and its checkpoint that still counts as
+/// reproducing the session
+const REPLAY_TOLERANCE: f32 = 1e-3;
+
+#[derive(Resource)]
+struct Replay {
+    events: VecDeque<RecordedEvent>,
+    /// Events of the tick being simulated
+    current: Vec<RecordedEvent>,
+    tick: u32,
+    characters: HashMap<ClientId, Entity>,
+    checkpoints: u32,
+    max_error: f32,
+    /// Tick and client of the first checkpoint that wasn't reproduced
+    first_divergence: Option<(u32, ClientId)>,
+}
+
+/// How closely a replay followed its recording
+pub(crate) struct ReplayReport {
+    pub(crate) ticks: u32,
+    pub(crate) checkpoints: u32,
+    pub(crate) max_error: f32,
+    /// Tick and client of the first checkpoint that wasn't reproduced
+    pub(crate) first_divergence: Option<(u32, ClientId)>,
+}
+
+/// Replay the recording and fail if the characters don't follow the recorded trajectories
+pub(crate) fn run(settings: &ZinnobreIronSettings, path: &str) -> anyhow::Result<()> {
+    init_logging();
+    let report = replay(settings, path)?;
+    info!(
+        ticks = report.ticks,
+        checkpoints = report.checkpoints,
+        max_error = report.max_error,
+        "Replay finished"
+    );
+    if let Some((tick, client_id)) = report.first_divergence {
+        bail!(
+            "the replay diverged from the recording by up to {} m, first at tick {tick} for \
+             client {client_id:?}",
+            report.max_error
+        );
+    }
+    Ok(())
+}
+
+/// Replay the recording to the end, comparing the characters with every checkpoint
+pub(crate) fn replay(settings: &ZinnobreIronSettings, path: &str) -> anyhow::Result<ReplayReport> {
+    let (header, events) = load_recording(path)?;
+    let level = load_level(&header.level)
+        .with_context(|| format!("could not load the recorded level {}", header.level))?;
+
+    let mut app = headless_app();
+    // The protocol needs lightyear's plugins, but the server is never started
+    app.add_plugins(ServerPlugins {
+        config: ServerConfig {
+            shared: shared_config(Mode::Separate),
+            net: vec![],
+            ..default()
+        },
+    });
+    app.add_plugins(settings.shared_plugin());
+    app.insert_resource(CurrentLevel(level));
+    app.insert_resource(Replay {
+        events,
+        current: Vec::new(),
+        tick: 0,
+        characters: HashMap::default(),
+        checkpoints: 0,
+        max_error: 0.0,
+        first_divergence: None,
+    });
+    // One tick per update, as fast as possible
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+        1.0 / FIXED_TIMESTEP_HZ,
+    )));
+    app.add_event::<HitscanShot>();
+    app.add_event::<DamageEvent>();
+    app.add_systems(Startup, spawn_initial_level);
+    app.add_systems(
+        FixedUpdate,
+        (
+            start_replay_tick,
+            handle_character_actions,
+            handle_ability_actions,
+            handle_interactions::<()>,
+            handle_projectiles,
+            apply_deaths_and_respawns,
+        )
+            .chain()
+            .in_set(FixedSet::Main),
+    );
+    app.add_systems(FixedUpdate, check_checkpoints.after(FixedSet::Physics));
+    app.finish();
+    app.cleanup();
+
+    info!(path, level = header.level, "Replaying recording");
+    while !app.world().resource::<Replay>().events.is_empty() {
+        app.update();
+    }
+
+    let replay = app.world().resource::<Replay>();
+    Ok(ReplayReport {
+        ticks: replay.tick,
+        checkpoints: replay.checkpoints,
+        max_error: replay.max_error,
+        first_divergence: replay.first_divergence,
+    })
+}
+
+fn load_recording(path: &str) -> anyhow::Result<(RecordingHeader, VecDeque<RecordedEvent>)> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("could not read the recording {path}"))?;
+    let mut lines = content.lines();
+    let header =
+        ron::de::from_str::<RecordingHeader>(lines.next().context("the recording is empty")?)
+            .context("could not parse the recording header")?;
+    let events = lines
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            ron::de::from_str::<RecordedEvent>(line)
+                .with_context(|| format!("could not parse line {} of the recording", index + 2))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok((header, events))
+}
+
+/// The level geometry and the props, without replication
+fn spawn_level(commands: &mut Commands, level: &LevelDefinition) {
+    spawn_level_geometry(commands, level);
+    for prop in &level.props {
+        commands.spawn((
+            Name::new("Prop"),
+            PropPhysicsBundle::new(&prop.prop),
+            prop.prop.clone(),
+            LevelEntity,
+            Position::new(prop.position),
+        ));
+    }
+}
+
+fn spawn_initial_level(mut commands: Commands, current_level: Res<CurrentLevel>) {
+    spawn_level(&mut commands, &current_level.0);
+}
+
+/// Take the events of this tick and apply the ones that happened before its inputs
+fn start_replay_tick(
+    mut commands: Commands,
+    mut replay: ResMut<Replay>,
+    mut movement_config: ResMut<MovementConfig>,
+    mut current_level: ResMut<CurrentLevel>,
+    level_query: Query<Entity, With<LevelEntity>>,
+) {
+    let replay = &mut *replay;
+    while replay
+        .events
+        .front()
+        .is_some_and(|event| event.tick() <= replay.tick)
+    {
+        replay.current.extend(replay.events.pop_front());
+    }
+
+    for event in &replay.current {
+        match event {
+            RecordedEvent::MovementProfile { config, .. } => {
+                *movement_config = config.clone();
+            }
+            RecordedEvent::LevelChanged { name, .. } => {
+                let Some(level) = load_level(name) else {
+                    warn!(name, "Could not load the level the recording changed to");
+                    continue;
+                };
+                despawn_level(&mut commands, &level_query);
+                spawn_level(&mut commands, &level);
+                current_level.0 = level;
+            }
+            RecordedEvent::Spawned {
+                client_id,
+                position,
+                ..
+            } => {
+                let character = commands
+                    .spawn((
+                        Name::new("Character"),
+                        Position(*position),
+                        CharacterPhysicsBundle::new(&movement_config, false),
+                        CharacterController::default(),
+                        ActionState::<CharacterAction>::default(),
+                        AbilitiesBundle::default(),
+                        PlayerId(*client_id),
+                        HeldProp::default(),
+                        CharacterMarker,
+                    ))
+                    .id();
+                replay.characters.insert(*client_id, character);
+            }
+            RecordedEvent::Disconnected { client_id, .. } => {
+                if let Some(character) = replay.characters.remove(client_id) {
+                    commands.entity(character).despawn();
+                }
+            }
+            RecordedEvent::Input {
+                client_id, input, ..
+            } => {
+                let Some(character) = replay.characters.get(client_id) else {
+                    continue;
+                };
+                let mut action_state = ActionState::<CharacterAction>::default();
+                let mut abilities = ActionState::<Ability>::default();
+                input.apply(&mut action_state, &mut abilities);
+                commands
+                    .entity(*character)
+                    .insert((action_state, abilities));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Deaths and respawns happen after the inputs, they were caused by damage we don't replay
+fn apply_deaths_and_respawns(
+    mut commands: Commands,
+    replay: Res<Replay>,
+    mut query: Query<(
+        &mut Position,
+        &mut LinearVelocity,
+        &mut AngularVelocity,
+        &mut CharacterController,
+    )>,
+) {
+    for event in &replay.current {
+        match event {
+            RecordedEvent::Died { client_id, .. } => {
+                if let Some(character) = replay.characters.get(client_id) {
+                    // The respawn is replayed from the recording, the tick is never read
+                    commands.entity(*character).insert(Dead {
+                        respawn_tick: Tick(0),
+                    });
+                }
+            }
+            RecordedEvent::Respawned {
+                client_id,
+                position: respawn_position,
+                ..
+            } => {
+                let Some(character) = replay.characters.get(client_id) else {
+                    continue;
+                };
+                let Ok((mut position, mut linear_velocity, mut angular_velocity, mut controller)) =
+                    query.get_mut(*character)
+                else {
+                    continue;
+                };
+                position.0 = *respawn_position;
+                linear_velocity.0 = Vec3::ZERO;
+                angular_velocity.0 = Vec3::ZERO;
+                *controller = CharacterController {
+                    crouching: controller.crouching,
+                    ..Default::default()
+                };
+                commands.entity(*character).remove::<Dead>();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Compare the characters with the server's checkpoints, then move on to the next tick
+fn check_checkpoints(mut replay: ResMut<Replay>, query: Query<&Position>) {
+    let replay = &mut *replay;
+    for event in replay.current.drain(..) {
+        let RecordedEvent::Checkpoint {
+            tick,
+            client_id,
+            position,
+        } = event
+        else {
+            continue;
+        };
+        let Some(replayed) = replay
+            .characters
+            .get(&client_id)
+            .and_then(|character| query.get(*character).ok())
+        else {
+            warn!(
+                tick,
+                ?client_id,
+                "Checkpoint for a character the replay doesn't have"
+            );
+            continue;
+        };
+        let error = replayed.0.distance(position);
+        replay.checkpoints += 1;
+        replay.max_error = replay.max_error.max(error);
+        if error > REPLAY_TOLERANCE && replay.first_divergence.is_none() {
+            replay.first_divergence = Some((tick, client_id));
+        }
+    }
+    replay.tick += 1;
+}
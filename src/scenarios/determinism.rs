@@ -0,0 +1,172 @@
+//! Determinism of the character movement between the server and a predicting client
+//!
+//! apply_character_action runs on both sides; if they disagree for the same inputs, for
+//! example because a system moved in or out of FixedSet::Main, the client keeps rolling
+//! back. A fixed input sequence is played on one client under several network conditions,
+//! and the prediction error of its character and the rollbacks are checked.
+use anyhow::{bail, Context};
+use bevy::log::info;
+use bevy::math::Vec2;
+
+use super::{connected_harness, settings, POSITION_TOLERANCE};
+use crate::app::harness::{Harness, SETTLE_FRAMES};
+use crate::app::settings::Conditioner;
+use crate::netcode::mispredictions::{MispredictionStats, PhysicsComponent};
+use crate::netcode::protocol::CharacterAction;
+use crate::ZinnobreIronSettings;
+
+/// Largest position error allowed between the prediction and the server, in meters
+const MAX_POSITION_ERROR: f32 = 0.01;
+/// Rollbacks allowed over the whole sequence, for corrections unrelated to our inputs
+const MAX_ROLLBACKS: f64 = 2.0;
+
+/// Inputs held for a number of frames
+struct InputStep {
+    frames: usize,
+    movement: Vec2,
+    jump: bool,
+    sprint: bool,
+    crouch: bool,
+}
+
+impl InputStep {
+    const fn walk(frames: usize, movement: Vec2) -> Self {
+        Self {
+            frames,
+            movement,
+            jump: false,
+            sprint: false,
+            crouch: false,
+        }
+    }
+}
+
+/// Walking, sprinting, jumping, crouching and standing still, with sharp direction changes
+const INPUT_SEQUENCE: &[InputStep] = &[
+    InputStep::walk(32, Vec2::Y),
+    InputStep::walk(16, Vec2::X),
+    InputStep {
+        sprint: true,
+        ..InputStep::walk(32, Vec2::NEG_Y)
+    },
+    InputStep {
+        jump: true,
+        ..InputStep::walk(4, Vec2::NEG_X)
+    },
+    InputStep::walk(28, Vec2::NEG_X),
+    InputStep {
+        crouch: true,
+        ..InputStep::walk(24, Vec2::new(0.7, 0.7))
+    },
+    InputStep::walk(16, Vec2::ZERO),
+    InputStep {
+        jump: true,
+        sprint: true,
+        ..InputStep::walk(4, Vec2::Y)
+    },
+    InputStep::walk(40, Vec2::new(-0.7, 0.7)),
+    InputStep::walk(16, Vec2::ZERO),
+];
+
+/// Network conditions the sequence is played under
+fn conditions() -> [(&'static str, Option<Conditioner>); 4] {
+    let conditioner = |latency_ms, jitter_ms, packet_loss| {
+        Some(Conditioner {
+            latency_ms,
+            jitter_ms,
+            packet_loss,
+        })
+    };
+    [
+        ("perfect", None),
+        ("latency", conditioner(50, 0, 0.0)),
+        ("jitter", conditioner(80, 20, 0.0)),
+        ("loss", conditioner(50, 10, 0.05)),
+    ]
+}
+
+/// The same inputs give the same movement on the server and on the predicting client
+#[test]
+fn determinism() -> anyhow::Result<()> {
+    let mut failures = Vec::new();
+    for (name, conditioner) in conditions() {
+        let mut settings = settings();
+        settings.common.client.conditioner = conditioner.clone();
+        settings.common.server.conditioner = conditioner;
+        if let Err(err) = play_sequence(&settings, name) {
+            failures.push(format!("{name}: {err:#}"));
+        }
+    }
+    if !failures.is_empty() {
+        bail!(failures.join("; "));
+    }
+    Ok(())
+}
+
+fn play_sequence(settings: &ZinnobreIronSettings, name: &str) -> anyhow::Result<()> {
+    let mut harness = connected_harness(settings, 1)?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    // Corrections while the character spawns and lands don't count
+    harness
+        .client_world_mut(0)
+        .resource_mut::<MispredictionStats>()
+        .0
+        .clear();
+    let rollbacks_before = harness.rollbacks(0);
+
+    for step in INPUT_SEQUENCE {
+        play_step(&mut harness, step)?;
+    }
+    harness.frame_step_n(SETTLE_FRAMES);
+
+    let character = harness
+        .controlled_character(0)
+        .context("the client lost its character")?;
+    let (mispredictions, max_error) = harness
+        .client_world_mut(0)
+        .resource::<MispredictionStats>()
+        .0
+        .get(&(character, PhysicsComponent::Position))
+        .map_or((0, 0.0), |stats| (stats.count, stats.max));
+    let rollbacks = harness.rollbacks(0) - rollbacks_before;
+    info!(
+        conditions = name,
+        mispredictions, max_error, rollbacks, "Prediction of the input sequence"
+    );
+
+    if max_error > MAX_POSITION_ERROR {
+        bail!("position mispredicted by up to {max_error} m ({mispredictions} times)");
+    }
+    if rollbacks > MAX_ROLLBACKS {
+        bail!("{rollbacks} rollbacks");
+    }
+    let server = harness
+        .server_position(0)
+        .context("the server has no character for the client")?;
+    let predicted = harness
+        .character_position(0, 0)
+        .context("the client doesn't show its character")?;
+    if server.distance(predicted) > POSITION_TOLERANCE {
+        bail!("the character ends at {predicted} on the client and {server} on the server");
+    }
+    Ok(())
+}
+
+fn play_step(harness: &mut Harness, step: &InputStep) -> anyhow::Result<()> {
+    harness.set_input(0, |action_state| {
+        action_state.set_axis_pair(&CharacterAction::Move, step.movement);
+        for (action, pressed) in [
+            (CharacterAction::Jump, step.jump),
+            (CharacterAction::Sprint, step.sprint),
+            (CharacterAction::Crouch, step.crouch),
+        ] {
+            if pressed {
+                action_state.press(&action);
+            } else {
+                action_state.release(&action);
+            }
+        }
+    })?;
+    harness.frame_step_n(step.frames);
+    Ok(())
+}
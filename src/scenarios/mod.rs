@@ -0,0 +1,327 @@
+//! Scenarios run headless on the Harness, checking the replicated world state
+//!
+//! Each scenario is a test, run them with `cargo test scenarios`.
+use std::f32::consts::TAU;
+use std::ops::Range;
+
+use anyhow::{bail, ensure, Context};
+use avian3d::prelude::Position;
+use bevy::log::info;
+use bevy::math::{Vec2, Vec3};
+use bevy::prelude::{Entity, Or, With, World};
+use lightyear::client::prediction::diagnostics::PredictionDiagnosticsPlugin;
+use lightyear::prelude::client::{Interpolated, Predicted};
+use lightyear::prelude::ClientId;
+
+use crate::app::harness::{game_harness, Harness, CONNECT_FRAMES, FIRST_CLIENT_ID, SETTLE_FRAMES};
+use crate::app::settings::{read_settings, Conditioner};
+use crate::app::shared::{FIXED_TIMESTEP_HZ, REPLICATION_INTERVAL};
+use crate::netcode::protocol::{CharacterAction, CharacterMarker, PlayerId};
+use crate::netcode::remote_input::RemoteInputPolicy;
+use crate::netcode::replication::ReplicationMode;
+use crate::team::Team;
+use crate::ZinnobreIronSettings;
+
+mod determinism;
+mod projectile;
+mod replay;
+
+/// Frames to wait for something to be replicated
+const REPLICATION_FRAMES: usize = 200;
+/// Frames each measurement runs for
+const MEASURE_FRAMES: usize = 256;
+/// How often a zig-zagging character changes direction
+const ZIGZAG_FRAMES: usize = 16;
+/// Clients in the interest management scenario, one watching the others
+const CROWD_SIZE: usize = 8;
+/// Radius of the ring the watched clients stand on
+const CROWD_RADIUS: f32 = 3.0;
+/// Bandwidth with the crowd out of range, relative to with the crowd around the client.
+/// Only the client's own character and static entities are left, so well under half
+const MAX_FAR_BANDWIDTH_RATIO: f64 = 0.5;
+/// Rollbacks allowed when only the client's own character is predicted, for corrections
+/// unrelated to the inputs
+const MAX_OWN_ROLLBACKS: f64 = 2.0;
+/// How far a client may show a character from where the server has it once it stopped
+const POSITION_TOLERANCE: f32 = 0.1;
+
+/// The game's settings, which the scenarios change where they need to
+fn settings() -> ZinnobreIronSettings {
+    read_settings(include_str!("../../assets/settings.ron"))
+}
+
+/// Build a harness with the game's plugins and connect its clients
+fn connected_harness(
+    settings: &ZinnobreIronSettings,
+    num_clients: usize,
+) -> anyhow::Result<Harness> {
+    let mut harness = game_harness(settings, num_clients);
+    harness
+        .connect(CONNECT_FRAMES)
+        .context("the clients didn't connect")?;
+    Ok(harness)
+}
+
+/// Queries on the server and client worlds
+impl Harness {
+    fn client_id(&self, client: usize) -> ClientId {
+        ClientId::Netcode(FIRST_CLIENT_ID + client as u64)
+    }
+
+    fn server_world_mut(&mut self) -> &mut World {
+        self.server_app.world_mut()
+    }
+
+    /// The server's character of the client
+    fn server_character(&mut self, client: usize) -> Option<Entity> {
+        let client_id = self.client_id(client);
+        let world = self.server_world_mut();
+        world
+            .query_filtered::<(Entity, &PlayerId), With<CharacterMarker>>()
+            .iter(world)
+            .find(|(_, player_id)| player_id.0 == client_id)
+            .map(|(entity, _)| entity)
+    }
+
+    fn server_position(&mut self, client: usize) -> Option<Vec3> {
+        let entity = self.server_character(client)?;
+        self.server_world_mut()
+            .get::<Position>(entity)
+            .map(|position| position.0)
+    }
+
+    /// Move the client's character on the server, the clients are corrected by replication
+    fn teleport(&mut self, client: usize, position: Vec3) -> anyhow::Result<()> {
+        let Some(entity) = self.server_character(client) else {
+            bail!("the server has no character for client {client}");
+        };
+        if let Some(mut current) = self.server_world_mut().get_mut::<Position>(entity) {
+            current.0 = position;
+        }
+        Ok(())
+    }
+
+    /// Number of characters the client shows, predicted or interpolated
+    fn character_count(&mut self, client: usize) -> usize {
+        let world = self.client_world_mut(client);
+        world
+            .query_filtered::<(), (
+                With<CharacterMarker>,
+                Or<(With<Predicted>, With<Interpolated>)>,
+            )>()
+            .iter(world)
+            .count()
+    }
+
+    /// Where the client shows the character of `owner`
+    fn character_position(&mut self, client: usize, owner: usize) -> Option<Vec3> {
+        let owner_id = self.client_id(owner);
+        let world = self.client_world_mut(client);
+        world
+            .query_filtered::<(&Position, &PlayerId), (
+                With<CharacterMarker>,
+                Or<(With<Predicted>, With<Interpolated>)>,
+            )>()
+            .iter(world)
+            .find(|(_, player_id)| player_id.0 == owner_id)
+            .map(|(position, _)| position.0)
+    }
+
+    /// Another client whose character is in the same team as the client's
+    fn teammate(&mut self, client: usize) -> Option<usize> {
+        let team = |harness: &mut Harness, client: usize| {
+            let entity = harness.server_character(client)?;
+            harness.server_world_mut().get::<Team>(entity).copied()
+        };
+        let own_team = team(self, client)?;
+        (0..self.client_apps.len())
+            .filter(|other| *other != client)
+            .find(|other| team(self, *other) == Some(own_team))
+    }
+
+    /// Number of rollbacks the client did so far
+    fn rollbacks(&self, client: usize) -> f64 {
+        self.client_diagnostic(client, &PredictionDiagnosticsPlugin::ROLLBACKS)
+            .unwrap_or_default()
+    }
+}
+
+/// Step the harness while the clients walk left and right
+fn zigzag(harness: &mut Harness, clients: Range<usize>, frames: usize) -> anyhow::Result<()> {
+    for frame in 0..frames {
+        if frame % ZIGZAG_FRAMES == 0 {
+            let direction = if (frame / ZIGZAG_FRAMES) % 2 == 0 {
+                Vec2::X
+            } else {
+                Vec2::NEG_X
+            };
+            for client in clients.clone() {
+                harness.set_input(client, |action_state| {
+                    action_state.set_axis_pair(&CharacterAction::Move, direction)
+                })?;
+            }
+        }
+        harness.frame_step();
+    }
+    for client in clients {
+        harness.set_input(client, |action_state| {
+            action_state.set_axis_pair(&CharacterAction::Move, Vec2::ZERO)
+        })?;
+    }
+    Ok(())
+}
+
+/// Every client gets a character and sees the other players'
+#[test]
+fn connect() -> anyhow::Result<()> {
+    let mut harness = connected_harness(&settings(), 2)?;
+    for client in 0..2 {
+        ensure!(
+            harness.server_character(client).is_some(),
+            "the server has no character for client {client}"
+        );
+    }
+    harness
+        .wait_until(REPLICATION_FRAMES, |harness| {
+            (0..2).all(|client| harness.character_count(client) == 2)
+        })
+        .context("the characters weren't replicated to every client")
+}
+
+/// Inputs move the character on the server, and every client ends up showing it there
+#[test]
+fn movement() -> anyhow::Result<()> {
+    let mut harness = connected_harness(&settings(), 2)?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    let start = harness
+        .server_position(0)
+        .context("the server has no character for client 0")?;
+
+    harness.set_input(0, |action_state| {
+        action_state.set_axis_pair(&CharacterAction::Move, Vec2::Y)
+    })?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    harness.set_input(0, |action_state| {
+        action_state.set_axis_pair(&CharacterAction::Move, Vec2::ZERO)
+    })?;
+    harness.frame_step_n(SETTLE_FRAMES);
+
+    let end = harness
+        .server_position(0)
+        .context("the server has no character for client 0")?;
+    ensure!(
+        start.distance(end) > 1.0,
+        "the character didn't move on the server: {start} -> {end}"
+    );
+    for client in 0..2 {
+        let shown = harness
+            .character_position(client, 0)
+            .with_context(|| format!("client {client} doesn't show client 0's character"))?;
+        ensure!(
+            shown.distance(end) < POSITION_TOLERANCE,
+            "client {client} shows the character at {shown}, the server has it at {end}"
+        );
+    }
+    Ok(())
+}
+
+/// A crowd out of the interest radius costs a fraction of the bandwidth of one around us
+#[test]
+fn interest_bandwidth() -> anyhow::Result<()> {
+    let settings = settings();
+    let mut harness = connected_harness(&settings, CROWD_SIZE)?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    let origin = harness
+        .server_position(0)
+        .context("the server has no character for client 0")?;
+    let far = (settings.interest.radius + settings.interest.hysteresis) * 3.0;
+    let crowd = 1..CROWD_SIZE;
+
+    // Put the crowd in a ring around `center`
+    let mut kilobytes_in = |center: Vec3| -> anyhow::Result<f64> {
+        for client in crowd.clone() {
+            let angle = TAU * client as f32 / crowd.len() as f32;
+            let offset = Vec3::new(angle.cos(), 0.0, angle.sin()) * CROWD_RADIUS;
+            harness.teleport(client, center + offset)?;
+        }
+        // Let relevance catch up before measuring
+        zigzag(&mut harness, crowd.clone(), SETTLE_FRAMES)?;
+        let mut total = 0.0;
+        for _ in 0..MEASURE_FRAMES / ZIGZAG_FRAMES {
+            zigzag(&mut harness, crowd.clone(), ZIGZAG_FRAMES)?;
+            total += harness.kilobytes_in(0);
+        }
+        Ok(total / (MEASURE_FRAMES / ZIGZAG_FRAMES) as f64)
+    };
+    let near_kilobytes = kilobytes_in(origin)?;
+    let far_kilobytes = kilobytes_in(origin + Vec3::new(far, 0.0, 0.0))?;
+    info!(
+        near_kilobytes,
+        far_kilobytes, "KB/s received by client 0 with the crowd near and far"
+    );
+    ensure!(
+        far_kilobytes < near_kilobytes * MAX_FAR_BANDWIDTH_RATIO,
+        "interest management didn't reduce the bandwidth enough: {near_kilobytes:.1} KB/s \
+         near, {far_kilobytes:.1} KB/s far"
+    );
+    Ok(())
+}
+
+/// Rollbacks caused by a zig-zagging teammate, for each remote input policy
+///
+/// Each policy is checked against its own bound rather than against the others, the counts
+/// of two runs are too noisy to compare. The conditioner only adds latency, so the remote
+/// inputs are always late and never lost.
+#[test]
+fn remote_input_rollbacks() -> anyhow::Result<()> {
+    // A rollback needs a confirmed update to compare with, there is one per replication interval
+    let max_rollbacks =
+        MEASURE_FRAMES as f64 / (REPLICATION_INTERVAL.as_secs_f64() * FIXED_TIMESTEP_HZ) + 1.0;
+    for policy in [
+        RemoteInputPolicy::RepeatLast,
+        RemoteInputPolicy::DecayToZero,
+        RemoteInputPolicy::Interpolate,
+    ] {
+        let mut settings = settings();
+        settings.remote_input_policy = policy;
+        // The predicting policies only matter for the remote characters we predict
+        settings.replication_modes.characters = ReplicationMode::PredictAll;
+        settings.common.client.conditioner = Some(Conditioner {
+            latency_ms: 100,
+            jitter_ms: 0,
+            packet_loss: 0.0,
+        });
+
+        // Enemies are interpolated, so client 0 needs a teammate to predict
+        let mut harness = connected_harness(&settings, 3)?;
+        let teammate = harness.teammate(0).context("client 0 has no teammate")?;
+        harness.frame_step_n(SETTLE_FRAMES);
+        let before = harness.rollbacks(0);
+        zigzag(&mut harness, teammate..teammate + 1, MEASURE_FRAMES)?;
+        let policy_rollbacks = harness.rollbacks(0) - before;
+        info!(
+            ?policy,
+            rollbacks = policy_rollbacks,
+            frames = MEASURE_FRAMES,
+            "Rollbacks of client 0 while client {teammate} zig-zags"
+        );
+        if policy == RemoteInputPolicy::Interpolate {
+            // Only our own, perfectly known inputs are predicted
+            ensure!(
+                policy_rollbacks <= MAX_OWN_ROLLBACKS,
+                "{policy_rollbacks} rollbacks with {policy:?}, client 0 still predicts others"
+            );
+        } else {
+            ensure!(
+                policy_rollbacks > 0.0,
+                "no rollback with {policy:?}, client 0 doesn't predict client {teammate}"
+            );
+            ensure!(
+                policy_rollbacks <= max_rollbacks,
+                "{policy_rollbacks} rollbacks with {policy:?}, more than the {max_rollbacks} \
+                 confirmed updates received"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! A projectile fired by a player, from the pre-spawned copy on the shooting client to the
+//! damage it deals on the server
+use anyhow::{ensure, Context};
+use avian3d::prelude::CollidingEntities;
+use bevy::math::{Vec2, Vec3};
+use bevy::prelude::{Entity, With};
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::client::{PreSpawnedPlayerObject, Predicted};
+
+use super::{connected_harness, settings, REPLICATION_FRAMES};
+use crate::app::harness::{Harness, SETTLE_FRAMES};
+use crate::combat::projectile::{PROJECTILE_DAMAGE, PROJECTILE_LIFETIME_TICKS};
+use crate::netcode::protocol::{Ability, CharacterAction, Health, Projectile};
+
+/// How far in front of the shooter the target stands, far enough for the server's projectile
+/// to be replicated and matched before it hits
+const TARGET_DISTANCE: f32 = 10.0;
+
+impl Harness {
+    /// Change the ability inputs of the character the client controls
+    fn set_abilities(
+        &mut self,
+        client: usize,
+        f: impl FnOnce(&mut ActionState<Ability>),
+    ) -> anyhow::Result<()> {
+        let entity = self
+            .controlled_character(client)
+            .with_context(|| format!("client {client} doesn't control a character"))?;
+        let mut action_state = self
+            .client_world_mut(client)
+            .get_mut::<ActionState<Ability>>(entity)
+            .with_context(|| format!("client {client}'s character has no ability ActionState"))?;
+        f(&mut action_state);
+        Ok(())
+    }
+
+    /// The projectile the client pre-spawned, before or after it was matched
+    fn client_projectile(&mut self, client: usize) -> Option<Entity> {
+        let world = self.client_world_mut(client);
+        world
+            .query_filtered::<Entity, (With<Projectile>, With<PreSpawnedPlayerObject>)>()
+            .iter(world)
+            .next()
+    }
+
+    fn server_projectile(&mut self) -> Option<Entity> {
+        let world = self.server_world_mut();
+        world
+            .query_filtered::<Entity, With<Projectile>>()
+            .iter(world)
+            .next()
+    }
+
+    fn server_health(&mut self, client: usize) -> Option<f32> {
+        let entity = self.server_character(client)?;
+        self.server_world_mut()
+            .get::<Health>(entity)
+            .map(|health| health.current)
+    }
+}
+
+/// The shooter's pre-spawned projectile becomes the prediction of the server's, which flies
+/// through its touching shooter, hits the target and damages it, then despawns everywhere
+#[test]
+fn projectile() -> anyhow::Result<()> {
+    let mut harness = connected_harness(&settings(), 2)?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    let origin = harness
+        .server_position(0)
+        .context("the server has no character for client 0")?;
+    harness.teleport(1, origin + Vec3::X * TARGET_DISTANCE)?;
+    harness.frame_step_n(SETTLE_FRAMES);
+    let health_before = harness
+        .server_health(1)
+        .context("the target has no health")?;
+
+    harness.set_input(0, |action_state| {
+        action_state.set_axis_pair(&CharacterAction::Aim, Vec2::X)
+    })?;
+    harness.set_abilities(0, |action_state| action_state.press(&Ability::Projectile))?;
+    harness.frame_step();
+    harness.set_abilities(0, |action_state| action_state.release(&Ability::Projectile))?;
+
+    harness
+        .wait_until(REPLICATION_FRAMES, |harness| {
+            harness.client_projectile(0).is_some() && harness.server_projectile().is_some()
+        })
+        .context("the projectile wasn't spawned on the client and the server")?;
+    let client_projectile = harness.client_projectile(0).unwrap();
+    let server_projectile = harness.server_projectile().unwrap();
+
+    // Make the server's projectile touch its shooter for the rest of its flight
+    let shooter = harness
+        .server_character(0)
+        .context("the server has no character for client 0")?;
+    harness
+        .server_world_mut()
+        .get_mut::<CollidingEntities>(server_projectile)
+        .context("the server's projectile has no CollidingEntities")?
+        .0
+        .insert(shooter);
+
+    harness
+        .wait_until(REPLICATION_FRAMES, |harness| {
+            harness
+                .client_world_mut(0)
+                .get::<Predicted>(client_projectile)
+                .is_some_and(|predicted| predicted.confirmed_entity.is_some())
+        })
+        .context("the pre-spawned projectile wasn't matched with the server's")?;
+
+    harness
+        .wait_until(PROJECTILE_LIFETIME_TICKS as usize, |harness| {
+            harness
+                .server_world_mut()
+                .get_entity(server_projectile)
+                .is_none()
+        })
+        .context("the server's projectile didn't despawn")?;
+    let health_after = harness
+        .server_health(1)
+        .context("the target has no health")?;
+    ensure!(
+        (health_before - health_after - PROJECTILE_DAMAGE).abs() < 1e-3,
+        "the target's health went from {health_before} to {health_after}, expected a hit of \
+         {PROJECTILE_DAMAGE}"
+    );
+
+    harness
+        .wait_until(REPLICATION_FRAMES, |harness| {
+            harness
+                .client_world_mut(0)
+                .get_entity(client_projectile)
+                .is_none()
+        })
+        .context("the client's projectile didn't despawn")
+}
@@ -0,0 +1,70 @@
+//! A recorded session replays to the same trajectories
+use anyhow::{ensure, Context};
+
+use super::{settings, zigzag};
+use crate::app::harness::{game_harness, CONNECT_FRAMES, SETTLE_FRAMES};
+use crate::combat::health::{MAX_HEALTH, RESPAWN_TICKS};
+use crate::combat::DamageEvent;
+use crate::level::rotation::ChangeLevel;
+use crate::level::CurrentLevel;
+use crate::netcode::recording::{InputRecorder, RecordingHeader};
+use crate::replay::replay;
+
+/// Frames the players walk around between the events of the session
+const WALK_FRAMES: usize = 128;
+
+/// Record a session with movement, a death and a level change, then replay it
+#[test]
+fn replay_reproduces_recording() -> anyhow::Result<()> {
+    let settings = settings();
+    let path = std::env::temp_dir().join(format!("replay-scenario-{}.ron", std::process::id()));
+    let path = path.to_str().context("the temporary path isn't UTF-8")?;
+
+    let mut harness = game_harness(&settings, 2);
+    let level = harness
+        .server_world_mut()
+        .resource::<CurrentLevel>()
+        .0
+        .name
+        .clone();
+    harness.server_app.insert_resource(InputRecorder::create(
+        path,
+        &RecordingHeader {
+            level: level.clone(),
+        },
+    ));
+    harness
+        .connect(CONNECT_FRAMES)
+        .context("the clients didn't connect")?;
+    zigzag(&mut harness, 0..2, WALK_FRAMES)?;
+
+    let victim = harness
+        .server_character(1)
+        .context("the server has no character for client 1")?;
+    harness.server_world_mut().send_event(DamageEvent {
+        target: victim,
+        amount: MAX_HEALTH,
+        instigator: None,
+    });
+    zigzag(&mut harness, 0..2, RESPAWN_TICKS as usize + SETTLE_FRAMES)?;
+
+    harness.server_world_mut().send_event(ChangeLevel);
+    harness.frame_step_n(SETTLE_FRAMES);
+    let changed_to = &harness.server_world_mut().resource::<CurrentLevel>().0.name;
+    ensure!(*changed_to != level, "the level didn't change from {level}");
+    zigzag(&mut harness, 0..2, WALK_FRAMES)?;
+    // Dropping the apps flushes the recording
+    drop(harness);
+
+    let report = replay(&settings, path);
+    let _ = std::fs::remove_file(path);
+    let report = report?;
+    ensure!(report.checkpoints > 0, "the recording has no checkpoint");
+    ensure!(
+        report.first_divergence.is_none(),
+        "the replay diverged by up to {} m, first at {:?}",
+        report.max_error,
+        report.first_divergence
+    );
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Teams, balanced team assignment and replication restricted to teammates.
+use bevy::color::palettes::css;
+use bevy::prelude::{Changed, Color, Commands, Component, Entity, Query, RemovedComponents, Res};
+use leafwing_abilities::prelude::{ChargeState, CooldownState};
+use lightyear::prelude::server::SyncTarget;
+use lightyear::prelude::{ClientId, NetworkTarget, OverrideTargetComponent};
+use serde::{Deserialize, Serialize};
+
+use crate::netcode::protocol::{Ability, PlayerId};
+use crate::netcode::replication::{ReplicationMode, ReplicationModes};
+
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+impl Team {
+    pub const ALL: [Team; 2] = [Team::Red, Team::Blue];
+
+    pub fn color(self) -> Color {
+        match self {
+            Team::Red => css::CRIMSON.into(),
+            Team::Blue => css::ROYAL_BLUE.into(),
+        }
+    }
+}
+
+/// The team with the fewest players, ties go to the first team in [`Team::ALL`]
+pub(crate) fn balanced_team(teams: impl IntoIterator<Item = Team>) -> Team {
+    let mut counts = [0usize; Team::ALL.len()];
+    for team in teams {
+        counts[team as usize] += 1;
+    }
+    Team::ALL
+        .into_iter()
+        .min_by_key(|team| counts[*team as usize])
+        .unwrap()
+}
+
+/// Only replicate ability cooldowns and charges of a character to its teammates.
+///
+/// Enemies still see the character move and shoot, they just can't tell when its abilities
+/// are ready. Without those they can't predict its abilities either, so when characters are
+/// predicted by everyone, enemies interpolate them instead. The targets are rebuilt whenever a
+/// character joins, leaves or changes team.
+pub(crate) fn update_team_replication(
+    mut commands: Commands,
+    replication_modes: Res<ReplicationModes>,
+    changed_query: Query<(), Changed<Team>>,
+    mut removed: RemovedComponents<Team>,
+    character_query: Query<(Entity, &Team, &PlayerId)>,
+) {
+    let removed = removed.read().count() > 0;
+    if changed_query.is_empty() && !removed {
+        return;
+    }
+    for (entity, team, _) in &character_query {
+        let teammates: Vec<ClientId> = character_query
+            .iter()
+            .filter(|(_, other_team, _)| *other_team == team)
+            .map(|(_, _, player_id)| player_id.0)
+            .collect();
+        if replication_modes.characters == ReplicationMode::PredictAll {
+            // A client's sync mode for a character never changes: teammates stay teammates
+            commands.entity(entity).insert(SyncTarget {
+                prediction: NetworkTarget::Only(teammates.clone()),
+                interpolation: NetworkTarget::AllExcept(teammates.clone()),
+            });
+        }
+        commands.entity(entity).insert((
+            OverrideTargetComponent::<CooldownState<Ability>>::new(NetworkTarget::Only(
+                teammates.clone(),
+            )),
+            OverrideTargetComponent::<ChargeState<Ability>>::new(NetworkTarget::Only(teammates)),
+        ));
+    }
+}